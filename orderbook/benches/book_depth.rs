@@ -0,0 +1,205 @@
+//! Compares the price-level `BookSide` against the previous design, a `Vec<Order>` that was
+//! re-sorted after every insert and scanned linearly to find an order by id.
+//!
+//! `rest_and_cancel` does the same book work on both: rest every order, then take each one off
+//! again by id, oldest first. `match_sweep` sends one bid that takes every ask on the book. The
+//! price-level side runs it through `match_order`, so it also pays for what the engine does
+//! beyond the book (locks, settlement, fees, events and the trade chain); the sorted vector runs
+//! the matching loop as it was before price levels, which did none of that.
+//!
+//! ```shell
+//! cargo bench --bench book_depth
+//! ```
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use orderbook::{
+    apply_action, match_order, Action, BookSide, MatchContext, Order, OrderType, Price, PriceTime,
+    Quantity, State, Trade,
+};
+use std::cmp::Reverse;
+
+const ADDRESS: &str = "0x1111111111111111111111111111111111111111";
+const TAKER: &str = "0x2222222222222222222222222222222222222222";
+
+/// `depth` bids spread over `depth / 4` price levels, numbered in arrival order.
+fn orders(depth: u64) -> Vec<Order> {
+    (0..depth)
+        .map(|i| {
+            let price = Price::new(10_000 - (i * 7919) % (depth / 4 + 1), 2);
+            let mut order = Order::limit(
+                &i.to_string(),
+                ADDRESS,
+                OrderType::Bid,
+                price,
+                Quantity::new(10, 0),
+            );
+            order.sequence = i + 1;
+            order
+        })
+        .collect()
+}
+
+/// The book as it was before price levels: push, then re-sort the whole side.
+fn legacy_rest(bids: &mut Vec<Order>, order: Order) {
+    bids.push(order);
+    bids.sort_by_key(|order| Reverse(order.price));
+}
+
+/// The cancel path before price levels: a linear scan for the id, then a shift of the tail.
+fn legacy_remove(bids: &mut Vec<Order>, id: &str) -> Option<Order> {
+    let index = bids.iter().position(|order| order.id == id)?;
+    Some(bids.remove(index))
+}
+
+fn bench_rest_and_cancel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rest_and_cancel");
+    for depth in [100u64, 1_000, 5_000] {
+        let orders = orders(depth);
+        group.bench_with_input(
+            BenchmarkId::new("price_levels", depth),
+            &orders,
+            |b, orders| {
+                b.iter(|| {
+                    let mut bids = BookSide::new(OrderType::Bid);
+                    for order in orders {
+                        bids.insert(order.clone());
+                    }
+                    for order in orders {
+                        bids.remove(&order.id).unwrap();
+                    }
+                    bids
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("sorted_vec", depth),
+            &orders,
+            |b, orders| {
+                b.iter(|| {
+                    let mut bids = Vec::new();
+                    for order in orders {
+                        legacy_rest(&mut bids, order.clone());
+                    }
+                    for order in orders {
+                        legacy_remove(&mut bids, &order.id).unwrap();
+                    }
+                    bids
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// `depth` asks of 10 spread over `depth / 4` price levels, numbered in arrival order.
+fn asks(depth: u64) -> Vec<Order> {
+    (0..depth)
+        .map(|i| {
+            let price = Price::new(10_000 + (i * 7919) % (depth / 4 + 1), 2);
+            let mut order = Order::limit(
+                &format!("a{}", i),
+                ADDRESS,
+                OrderType::Ask,
+                price,
+                Quantity::new(10, 0),
+            );
+            order.sequence = i + 1;
+            order
+        })
+        .collect()
+}
+
+/// A bid that crosses every ask in `asks(depth)` and takes all of them.
+fn sweep(depth: u64) -> Order {
+    Order::limit(
+        "sweep",
+        TAKER,
+        OrderType::Bid,
+        Price::new(20_000, 2),
+        Quantity::new(10 * depth, 0),
+    )
+}
+
+/// The matching loop as it was before price levels: fill against the front of the sorted side
+/// and shift the vector down whenever the front order is used up.
+fn legacy_match(asks: &mut Vec<Order>, mut bid: Order) -> Vec<Trade> {
+    let mut trades = Vec::new();
+    while !bid.quantity.is_zero() {
+        let Some(ask) = asks.first_mut() else {
+            break;
+        };
+        let price = ask.price.expect("resting asks have a limit price");
+        if bid.price.is_some_and(|limit| limit < price) {
+            break;
+        }
+        let quantity = ask.quantity.min(bid.quantity);
+        trades.push(Trade {
+            id: trades.len() as u64,
+            ask_order: ask.clone(),
+            bid_order: bid.clone(),
+            price,
+            quantity,
+            taker_side: OrderType::Bid,
+            maker_fee: 0,
+            taker_fee: 0,
+        });
+        ask.quantity -= quantity;
+        bid.quantity -= quantity;
+        if ask.quantity.is_zero() {
+            asks.remove(0);
+        }
+    }
+    trades
+}
+
+fn bench_match_sweep(c: &mut Criterion) {
+    let mut group = c.benchmark_group("match_sweep");
+    for depth in [100u64, 1_000, 5_000] {
+        let ctx = MatchContext::default();
+        let mut state = State::new();
+        let funds = [
+            (ADDRESS, state.config.base.address.clone()),
+            (TAKER, state.config.quote.address.clone()),
+        ];
+        for (address, token) in funds {
+            let deposit = Action::Deposit {
+                address: address.to_string(),
+                token,
+                amount: 10u128.pow(30),
+            };
+            apply_action(&mut state, deposit, &ctx).unwrap();
+        }
+        for ask in asks(depth) {
+            match_order(&mut state, ask, &ctx, &PriceTime).unwrap();
+        }
+        group.bench_with_input(
+            BenchmarkId::new("price_levels", depth),
+            &state,
+            |b, state| {
+                b.iter_batched(
+                    || state.clone(),
+                    |mut state| match_order(&mut state, sweep(depth), &ctx, &PriceTime).unwrap(),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+
+        let mut sorted = asks(depth);
+        sorted.sort_by_key(|order| order.price);
+        group.bench_with_input(
+            BenchmarkId::new("sorted_vec", depth),
+            &sorted,
+            |b, sorted| {
+                b.iter_batched(
+                    || sorted.clone(),
+                    |mut asks| legacy_match(&mut asks, sweep(depth)),
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rest_and_cancel, bench_match_sweep);
+criterion_main!(benches);
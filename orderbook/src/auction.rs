@@ -0,0 +1,176 @@
+//! Batch auction matching: orders collect on the book without trading, and at the end of each
+//! batch the book is crossed at one uniform clearing price.
+
+use crate::{
+    cancel_event, chain_trades, expire_orders, fee, release_all, release_excess, rest_order,
+    settle, ActionReport, MatchContext, Order, OrderEvent, OrderType, Price, Quantity, State,
+    TimeInForce, Trade, MAX_SCALE,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// The outcome of one auction that traded.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub struct Clearing {
+    /// The market that cleared, when run through an `Exchange`. Empty for a lone `State`.
+    pub market: String,
+    pub price: Price,
+    /// Quantity traded, the same on both sides. Saturates at the largest quantity the
+    /// market's scale can hold.
+    pub volume: Quantity,
+}
+
+/// The price at which the book clears, if any bid crosses any ask. Every limit price on the book
+/// is a candidate. The winner executes the most volume; ties go to the smallest imbalance between
+/// what is bid and offered at that price, then to the price closest to `last_price`, then to the
+/// lowest price.
+pub fn clearing_price(state: &State) -> Option<Price> {
+    let config = &state.config;
+    let bids: Vec<(Price, u128)> = state
+        .bids
+        .orders()
+        .map(|o| (o.resting_price(), config.base_amount(o.total_quantity())))
+        .collect();
+    let asks: Vec<(Price, u128)> = state
+        .asks
+        .orders()
+        .map(|o| (o.resting_price(), config.base_amount(o.total_quantity())))
+        .collect();
+    let candidates: BTreeSet<Price> = bids.iter().chain(&asks).map(|(price, _)| *price).collect();
+
+    candidates
+        .into_iter()
+        .filter_map(|price| {
+            // Saturating, since a deep enough book can outgrow a u128 of token units
+            let add = |sum: u128, (_, amount): &(Price, u128)| sum.saturating_add(*amount);
+            let demand = bids.iter().filter(|(bid, _)| *bid >= price).fold(0, add);
+            let supply = asks.iter().filter(|(ask, _)| *ask <= price).fold(0, add);
+            let volume = demand.min(supply);
+            let distance = state.last_price.map_or(0, |last| distance(price, last));
+            // Smaller is better in every position, so the ranking is a plain minimum
+            (volume > 0).then_some((
+                (u128::MAX - volume, demand.abs_diff(supply), distance, price),
+                price,
+            ))
+        })
+        .min()
+        .map(|(_, price)| price)
+}
+
+/// `|a - b|` in units of the finest scale, which fits a `u128` for any two prices.
+fn distance(a: Price, b: Price) -> u128 {
+    let units = |price: Price| price.ticks as u128 * 10u128.pow((MAX_SCALE - price.scale) as u32);
+    units(a).abs_diff(units(b))
+}
+
+/// Expires the orders that are past their time at `ctx`, then crosses the book at
+/// `clearing_price`. Eligible bids and asks are filled in price-time
+/// priority, paired off in that order, and every trade executes at the clearing price. Of each
+/// pair, the order that arrived later pays the taker fee. Afterwards any immediate-or-cancel
+/// order still on the book is cancelled.
+///
+/// Self-trade prevention only applies to continuous matching: a trader's bid and ask may be
+/// paired here like any other.
+pub fn clear_auction(state: &mut State, ctx: &MatchContext) -> (Option<Clearing>, ActionReport) {
+    let mut report = ActionReport::default();
+    expire_orders(state, ctx, &mut report.events);
+    let clearing = clearing_price(state).map(|price| {
+        let volume = cross_at(state, price, &mut report);
+        Clearing {
+            market: String::new(),
+            price,
+            volume,
+        }
+    });
+
+    for side in [OrderType::Bid, OrderType::Ask] {
+        let immediate: Vec<String> = state
+            .side(side)
+            .orders()
+            .filter(|order| order.time_in_force == TimeInForce::ImmediateOrCancel)
+            .map(|order| order.id.clone())
+            .collect();
+        for id in immediate {
+            let mut order = state
+                .side_mut(side)
+                .remove(&id)
+                .expect("order was just listed");
+            release_all(state, &mut order);
+            report.events.push(cancel_event(&mut order));
+        }
+    }
+    (clearing, report)
+}
+
+fn cross_at(state: &mut State, price: Price, report: &mut ActionReport) -> Quantity {
+    let mut bids = take_eligible(state, OrderType::Bid, |bid| bid >= price);
+    let mut asks = take_eligible(state, OrderType::Ask, |ask| ask <= price);
+    let mut volume = Quantity::zero(0);
+    let (mut i, mut j) = (0, 0);
+    while i < bids.len() && j < asks.len() {
+        let (bid, ask) = (&mut bids[i], &mut asks[j]);
+        let quantity = bid.total_quantity().min(ask.total_quantity());
+        let (maker, taker) = if bid.sequence < ask.sequence {
+            (&*bid, &*ask)
+        } else {
+            (&*ask, &*bid)
+        };
+        let quote = state.config.quote_amount(price, quantity);
+        let trade = Trade {
+            id: state.next_sequence(),
+            ask_order: ask.clone(),
+            bid_order: bid.clone(),
+            price,
+            quantity,
+            taker_side: taker.order_type,
+            maker_fee: fee(quote, state.config.fees.rates_for(&maker.address).maker_bps),
+            taker_fee: fee(quote, state.config.fees.rates_for(&taker.address).taker_bps),
+        };
+        settle(state, bid, ask, &trade);
+        state.trade_chain = chain_trades(state.trade_chain, std::slice::from_ref(&trade));
+        report.trades.push(trade);
+
+        bid.reduce(quantity);
+        ask.reduce(quantity);
+        report.events.push(OrderEvent::fill(bid, quantity));
+        report.events.push(OrderEvent::fill(ask, quantity));
+        volume = volume.saturating_add(quantity);
+        if bid.total_quantity().is_zero() {
+            release_all(state, bid);
+            i += 1;
+        }
+        if ask.total_quantity().is_zero() {
+            j += 1;
+        }
+    }
+    state.last_price = Some(price);
+
+    // What is left goes back with its time priority, holding only the funds it still needs
+    for mut order in bids.into_iter().skip(i).chain(asks.into_iter().skip(j)) {
+        release_excess(state, &mut order);
+        rest_order(state, order);
+    }
+    volume
+}
+
+/// Removes the orders whose price passes `eligible` from one side, in priority order.
+fn take_eligible(
+    state: &mut State,
+    side: OrderType,
+    eligible: impl Fn(Price) -> bool,
+) -> Vec<Order> {
+    let ids: Vec<String> = state
+        .side(side)
+        .orders()
+        .filter(|order| eligible(order.resting_price()))
+        .map(|order| order.id.clone())
+        .collect();
+    ids.iter()
+        .map(|id| {
+            state
+                .side_mut(side)
+                .remove(id)
+                .expect("order was just listed")
+        })
+        .collect()
+}
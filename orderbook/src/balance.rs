@@ -0,0 +1,267 @@
+use crate::{is_valid_address, MatchError};
+use merkle_tree::{hex_to_bytes, OrderbookMerkleTree};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+
+/// One trader's holding of one token, in the token's smallest unit.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub struct Balance {
+    pub available: u128,
+    /// Held by open orders until they fill or are cancelled.
+    pub locked: u128,
+}
+
+impl Balance {
+    pub fn total(&self) -> u128 {
+        self.available + self.locked
+    }
+}
+
+/// A balance as it stood at the end of a batch that changed it.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub struct BalanceUpdate {
+    pub trader: String,
+    pub token: String,
+    pub balance: Balance,
+}
+
+impl BalanceUpdate {
+    /// The balance tree leaf for this update. Leaves hold the total, locked funds included.
+    pub fn to_leaf(&self) -> merkle_tree::Balance {
+        merkle_tree::Balance {
+            user_address: address_bytes(&self.trader),
+            token_address: address_bytes(&self.token),
+            balance: self.balance.total(),
+        }
+    }
+}
+
+/// A valid address, which is lowercase, is keyed as `0x00` followed by its 20 bytes. Anything
+/// else, such as a placeholder token name, is keyed as `0x01` followed by the Keccak-256 of its
+/// UTF-8 bytes. The tag keeps the two apart and gives each a fixed length, so distinct strings,
+/// and distinct trader and token pairs, never share a leaf.
+fn address_bytes(address: &str) -> Vec<u8> {
+    match hex_to_bytes(address) {
+        Ok(bytes) if is_valid_address(address) => [&[0][..], &bytes].concat(),
+        _ => [
+            &[1][..],
+            &alloy_primitives::keccak256(address.as_bytes())[..],
+        ]
+        .concat(),
+    }
+}
+
+/// Balances of every trader by token. Also remembers which entries changed since the last
+/// `take_updates`, which is not serialized.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Balances {
+    accounts: BTreeMap<String, BTreeMap<String, Balance>>,
+    #[serde(skip)]
+    changed: BTreeSet<(String, String)>,
+}
+
+// The change set is bookkeeping for the current batch, not part of the balances themselves.
+impl PartialEq for Balances {
+    fn eq(&self, other: &Self) -> bool {
+        self.accounts == other.accounts
+    }
+}
+
+impl Eq for Balances {}
+
+impl Hash for Balances {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.accounts.hash(state);
+    }
+}
+
+impl Balances {
+    pub fn get(&self, trader: &str, token: &str) -> Balance {
+        self.accounts
+            .get(trader)
+            .and_then(|tokens| tokens.get(token))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Every `(trader, token, balance)`, ordered by trader and then token.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, Balance)> {
+        self.accounts.iter().flat_map(|(trader, tokens)| {
+            tokens
+                .iter()
+                .map(move |(token, balance)| (trader.as_str(), token.as_str(), *balance))
+        })
+    }
+
+    /// A balance tree holding every balance.
+    pub fn tree(&self) -> OrderbookMerkleTree {
+        let mut tree = OrderbookMerkleTree::new();
+        let leaves: Vec<merkle_tree::Balance> = self
+            .iter()
+            .map(|(trader, token, balance)| {
+                BalanceUpdate {
+                    trader: trader.to_string(),
+                    token: token.to_string(),
+                    balance,
+                }
+                .to_leaf()
+            })
+            .collect();
+        tree.batch_update(&leaves);
+        tree
+    }
+
+    /// Root of `tree`.
+    pub fn root(&self) -> [u8; 32] {
+        let root = self.tree().get_root();
+        root.try_into().expect("keccak roots are 32 bytes")
+    }
+
+    /// The final value of every balance changed since the last call, ordered by trader and token.
+    pub(crate) fn take_updates(&mut self) -> Vec<BalanceUpdate> {
+        std::mem::take(&mut self.changed)
+            .into_iter()
+            .map(|(trader, token)| {
+                let balance = self.get(&trader, &token);
+                BalanceUpdate {
+                    trader,
+                    token,
+                    balance,
+                }
+            })
+            .collect()
+    }
+
+    // Callers skip zero amounts so that an untouched balance is neither created nor reported
+    fn entry(&mut self, trader: &str, token: &str) -> &mut Balance {
+        self.changed.insert((trader.to_string(), token.to_string()));
+        self.accounts
+            .entry(trader.to_string())
+            .or_default()
+            .entry(token.to_string())
+            .or_default()
+    }
+
+    pub(crate) fn deposit(
+        &mut self,
+        trader: &str,
+        token: &str,
+        amount: u128,
+    ) -> Result<(), MatchError> {
+        let balance = self.get(trader, token);
+        let available = balance
+            .available
+            .checked_add(amount)
+            .filter(|sum| sum.checked_add(balance.locked).is_some());
+        let available = available.ok_or(MatchError::BalanceOverflow)?;
+        if amount == 0 {
+            return Ok(());
+        }
+        self.entry(trader, token).available = available;
+        Ok(())
+    }
+
+    /// Checks that `amount` is available to `trader` without changing anything.
+    pub(crate) fn check_available(
+        &self,
+        trader: &str,
+        token: &str,
+        amount: u128,
+    ) -> Result<(), MatchError> {
+        let available = self.get(trader, token).available;
+        if available < amount {
+            return Err(MatchError::InsufficientBalance {
+                token: token.to_string(),
+                required: amount,
+                available,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn withdraw(
+        &mut self,
+        trader: &str,
+        token: &str,
+        amount: u128,
+    ) -> Result<(), MatchError> {
+        self.check_available(trader, token, amount)?;
+        if amount == 0 {
+            return Ok(());
+        }
+        self.entry(trader, token).available -= amount;
+        Ok(())
+    }
+
+    /// Moves `amount` from available to locked. The caller has checked that it is available.
+    pub(crate) fn lock(&mut self, trader: &str, token: &str, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let balance = self.entry(trader, token);
+        balance.available = balance
+            .available
+            .checked_sub(amount)
+            .expect("lock was checked against the balance");
+        balance.locked += amount;
+    }
+
+    /// Moves `amount` from locked back to available.
+    pub(crate) fn unlock(&mut self, trader: &str, token: &str, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let balance = self.entry(trader, token);
+        balance.locked = balance
+            .locked
+            .checked_sub(amount)
+            .expect("unlocking more than is locked");
+        balance.available += amount;
+    }
+
+    /// Takes `amount` out of `trader`'s locked funds, as when it pays for a fill.
+    pub(crate) fn spend_locked(&mut self, trader: &str, token: &str, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let balance = self.entry(trader, token);
+        balance.locked = balance
+            .locked
+            .checked_sub(amount)
+            .expect("spending more than is locked");
+    }
+
+    pub(crate) fn credit(&mut self, trader: &str, token: &str, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let balance = self.entry(trader, token);
+        balance.available = balance.available.saturating_add(amount);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_bytes_never_collide() {
+        let lowercase = "0x00000000000000000000000000000000deadbeef";
+        let strings = [
+            "DEAD",
+            "0xDEAD",
+            "0xdead",
+            "dead",
+            lowercase,
+            "0x00000000000000000000000000000000DEADBEEF",
+        ];
+        let keys: BTreeSet<Vec<u8>> = strings.iter().map(|s| address_bytes(s)).collect();
+        assert_eq!(keys.len(), strings.len());
+        assert_eq!(
+            address_bytes(lowercase),
+            [&[0][..], &hex_to_bytes(lowercase).unwrap()].concat()
+        );
+        assert!(keys.iter().all(|key| key.len() == 21 || key.len() == 33));
+    }
+}
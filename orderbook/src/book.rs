@@ -0,0 +1,256 @@
+use crate::{Order, OrderType, Price};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+
+/// The orders resting at one price, keyed by their sequence number so that the oldest comes first.
+pub type Level = BTreeMap<u64, Order>;
+
+/// One side of the book as price levels, each holding its orders oldest first.
+///
+/// Insertion and best-price lookup are O(log n) in the number of levels. Levels serialize as a
+/// list ordered by price, so a given book always has the same encoding.
+///
+/// Lookups by order id and by trader go through indexes that are derived from the levels. They
+/// are not serialized, which keeps them out of the zkVM input, and are rebuilt on deserialization.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "StoredBookSide")]
+pub struct BookSide {
+    side: OrderType,
+    #[serde(with = "level_list")]
+    levels: BTreeMap<Price, Level>,
+    /// The price level and sequence number of each resting order, by id.
+    #[serde(skip)]
+    locations: BTreeMap<String, (Price, u64)>,
+    /// Ids of each trader's resting orders.
+    #[serde(skip)]
+    by_trader: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// The serialized part of a `BookSide`.
+#[derive(Deserialize)]
+struct StoredBookSide {
+    side: OrderType,
+    #[serde(with = "level_list")]
+    levels: BTreeMap<Price, Level>,
+}
+
+impl From<StoredBookSide> for BookSide {
+    fn from(stored: StoredBookSide) -> Self {
+        let mut book = Self::new(stored.side);
+        for order in stored.levels.into_values().flat_map(Level::into_values) {
+            book.insert(order);
+        }
+        book
+    }
+}
+
+// The indexes are derived from the levels, so they take no part in comparison or hashing.
+impl PartialEq for BookSide {
+    fn eq(&self, other: &Self) -> bool {
+        self.side == other.side && self.levels == other.levels
+    }
+}
+
+impl Eq for BookSide {}
+
+impl Hash for BookSide {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.side.hash(state);
+        self.levels.hash(state);
+    }
+}
+
+impl BookSide {
+    pub fn new(side: OrderType) -> Self {
+        Self {
+            side,
+            levels: BTreeMap::new(),
+            locations: BTreeMap::new(),
+            by_trader: BTreeMap::new(),
+        }
+    }
+
+    pub fn side(&self) -> OrderType {
+        self.side
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty()
+    }
+
+    /// Number of resting orders.
+    pub fn len(&self) -> usize {
+        self.levels.values().map(Level::len).sum()
+    }
+
+    /// Highest bid or lowest ask.
+    pub fn best_price(&self) -> Option<Price> {
+        match self.side {
+            OrderType::Bid => self.levels.keys().next_back().copied(),
+            OrderType::Ask => self.levels.keys().next().copied(),
+        }
+    }
+
+    /// The order at the front of the queue at the best price.
+    pub fn best_order(&self) -> Option<&Order> {
+        self.levels.get(&self.best_price()?)?.values().next()
+    }
+
+    /// Tidies up a resting order after it was filled or reduced: an iceberg whose displayed slice
+    /// is used up refills from its reserve and moves to the back of its level under a fresh
+    /// sequence number from `next_sequence`, and an order with nothing left leaves the book.
+    pub(crate) fn settle(&mut self, id: &str, next_sequence: impl FnOnce() -> u64) {
+        let Some(order) = self.get(id) else {
+            return;
+        };
+        if !order.quantity.is_zero() {
+            return;
+        }
+        let mut order = self.remove(id).expect("order was just found");
+        if order.hidden_quantity.is_zero() {
+            return;
+        }
+        let display = order.display_quantity.unwrap_or(order.hidden_quantity);
+        order.quantity = display.min(order.hidden_quantity);
+        order.hidden_quantity -= order.quantity;
+        order.sequence = next_sequence();
+        self.insert(order);
+    }
+
+    /// Adds `order` to its price level, queued by its sequence number. This only files the order:
+    /// it neither matches nor locks funds, which is what `match_order` is for.
+    pub fn insert(&mut self, order: Order) {
+        let price = order.resting_price();
+        self.locations
+            .insert(order.id.clone(), (price, order.sequence));
+        self.by_trader
+            .entry(order.address.clone())
+            .or_default()
+            .insert(order.id.clone());
+        self.levels
+            .entry(price)
+            .or_default()
+            .insert(order.sequence, order);
+    }
+
+    fn unindex(&mut self, id: &str, address: &str) {
+        self.locations.remove(id);
+        if let Some(ids) = self.by_trader.get_mut(address) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.by_trader.remove(address);
+            }
+        }
+    }
+
+    /// Price levels from best to worst.
+    pub fn levels(&self) -> impl Iterator<Item = (&Price, &Level)> {
+        let ascending = self.side == OrderType::Ask;
+        let forward = ascending.then(|| self.levels.iter());
+        let backward = (!ascending).then(|| self.levels.iter().rev());
+        forward
+            .into_iter()
+            .flatten()
+            .chain(backward.into_iter().flatten())
+    }
+
+    /// The orders resting at `price`, oldest first.
+    pub fn level(&self, price: Price) -> Option<&Level> {
+        self.levels.get(&price)
+    }
+
+    /// Resting orders in priority order: best price first, oldest first within a price.
+    pub fn orders(&self) -> impl Iterator<Item = &Order> {
+        self.levels().flat_map(|(_, level)| level.values())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Order> {
+        let (price, sequence) = self.locations.get(id)?;
+        self.levels.get(price)?.get(sequence)
+    }
+
+    /// Callers may change quantities but not the id, address, price or sequence, which the
+    /// indexes rely on.
+    pub(crate) fn get_mut(&mut self, id: &str) -> Option<&mut Order> {
+        let (price, sequence) = self.locations.get(id)?;
+        self.levels.get_mut(price)?.get_mut(sequence)
+    }
+
+    /// The resting orders of `address`, ordered by id.
+    pub fn orders_of<'a>(&'a self, address: &str) -> impl Iterator<Item = &'a Order> {
+        self.by_trader
+            .get(address)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.get(id))
+    }
+
+    /// Takes the order off the book without releasing its funds, which is what a cancel is for.
+    pub fn remove(&mut self, id: &str) -> Option<Order> {
+        let (price, sequence) = *self.locations.get(id)?;
+        let level = self.levels.get_mut(&price)?;
+        let order = level.remove(&sequence)?;
+        if level.is_empty() {
+            self.levels.remove(&price);
+        }
+        self.unindex(&order.id, &order.address);
+        Some(order)
+    }
+
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&Order) -> bool) {
+        let mut removed = vec![];
+        self.levels.retain(|_, level| {
+            level.retain(|_, order| {
+                let kept = keep(order);
+                if !kept {
+                    removed.push((order.id.clone(), order.address.clone()));
+                }
+                kept
+            });
+            !level.is_empty()
+        });
+        for (id, address) in removed {
+            self.unindex(&id, &address);
+        }
+    }
+}
+
+/// Serializes the level map as a sequence of `(price, orders)` pairs, which also keeps formats
+/// without non-string map keys (such as JSON) working. Orders carry their own sequence numbers, so
+/// each level is written as a plain list, oldest first.
+mod level_list {
+    use super::Level;
+    use crate::{Order, Price};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(
+        levels: &BTreeMap<Price, Level>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            levels
+                .iter()
+                .map(|(price, level)| (price, level.values().collect::<Vec<_>>())),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<Price, Level>, D::Error> {
+        let levels: Vec<(Price, Vec<Order>)> = Vec::deserialize(deserializer)?;
+        Ok(levels
+            .into_iter()
+            .map(|(price, orders)| {
+                (
+                    price,
+                    orders
+                        .into_iter()
+                        .map(|order| (order.sequence, order))
+                        .collect(),
+                )
+            })
+            .collect())
+    }
+}
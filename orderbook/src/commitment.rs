@@ -0,0 +1,803 @@
+//! The canonical byte encoding of a `State` and the commitment built on it.
+//!
+//! All integers are big-endian. The encoding starts with a version byte and then lists, in order:
+//!
+//! | field            | encoding                                                          |
+//! |------------------|-------------------------------------------------------------------|
+//! | version          | `u8`, currently `STATE_ENCODING_VERSION`                          |
+//! | config           | see `encode_config`                                               |
+//! | bids, asks       | `u32` order count, then each order best price first, oldest first |
+//! | trade chain      | 32 bytes                                                          |
+//! | collected fees   | `u128`                                                            |
+//! | last price       | option of price                                                   |
+//! | stop orders      | `u32` count, then each order oldest first                         |
+//! | sequence         | `u64`                                                             |
+//! | balances         | `u32` count, then trader, token, available `u128`, locked `u128`  |
+//!
+//! Balances are listed by trader and then token, and every order ends with the `u128` funds it has
+//! locked.
+//!
+//! An `Exchange` encodes as the version byte, a `u32` market count, each market as its id followed
+//! by the 32-byte commitment of its `State`, in id order, then its 32-byte trade chain and its
+//! balances as above.
+//!
+//! A batch of actions, which `actions_hash` commits to, is a `u32` count followed by each action as
+//! a `u8` tag and its fields: `0` place and the order as above, `1` cancel and the order id, `2`
+//! cancel-all and the address, `3` amend, the order id and the options of the new price and
+//! quantity, `4` deposit and `5` withdraw, each with the address, the token and the `u128` amount.
+//!
+//! The hashes over a batch's output use the same building blocks:
+//!
+//! - `event_hash` covers a `u32` count and then each event as a `u8` tag, `0` accepted, `1` rested,
+//!   `2` partially filled, `3` filled, `4` cancelled, `5` rejected, `6` expired or `7` self-trade
+//!   prevented, followed by its fields in declaration order. See `encode_event`.
+//! - `chain_trades` hashes the previous head followed by the trade: its `u64` id, the ask and bid
+//!   orders as above, the price, the quantity, the taker side as an order type and the maker and
+//!   taker fees as `u128`.
+//! - `clearings_hash` covers a `u32` count and then each clearing's market, price and volume.
+//!
+//! A string is a `u32` byte length followed by its UTF-8 bytes. An option is `0x00` for none or
+//! `0x01` followed by the value. A price or quantity is its `u64` units followed by its `u8` scale,
+//! after dropping trailing zeros, so values that compare equal encode the same way.
+
+use crate::{
+    Action, Allocation, Balances, Clearing, Exchange, FeeRates, MarketConfig, MatchError,
+    MatchingMode, Order, OrderEvent, OrderType, PostOnly, Price, PriceRule, ProRata, Quantity,
+    RejectReason, SelfTradePrevention, State, TimeInForce, Trade,
+};
+
+/// Bumped whenever the encoding below changes, so old and new commitments never collide.
+pub const STATE_ENCODING_VERSION: u8 = 8;
+
+impl State {
+    /// Keccak-256 of `canonical_encoding`. This is what `PublicValuesStruct` carries as
+    /// `prevState` and `newState`.
+    pub fn commitment(&self) -> [u8; 32] {
+        alloy_primitives::keccak256(self.canonical_encoding()).0
+    }
+
+    /// The versioned byte encoding described in the module docs.
+    pub fn canonical_encoding(&self) -> Vec<u8> {
+        let mut out = Encoder::default();
+        out.u8(STATE_ENCODING_VERSION);
+        encode_config(&mut out, &self.config);
+        for side in [&self.bids, &self.asks] {
+            out.u32(side.len() as u32);
+            for order in side.orders() {
+                encode_order(&mut out, order);
+            }
+        }
+        out.bytes(&self.trade_chain);
+        out.u128(self.collected_fees);
+        out.option(self.last_price, Encoder::price);
+        out.u32(self.stop_orders.len() as u32);
+        for order in &self.stop_orders {
+            encode_order(&mut out, order);
+        }
+        out.u64(self.sequence);
+        encode_balances(&mut out, &self.balances);
+        out.0
+    }
+}
+
+impl Exchange {
+    /// Keccak-256 of `canonical_encoding`: one root over every market and the shared balances.
+    pub fn commitment(&self) -> [u8; 32] {
+        alloy_primitives::keccak256(self.canonical_encoding()).0
+    }
+
+    /// The versioned byte encoding described in the module docs.
+    pub fn canonical_encoding(&self) -> Vec<u8> {
+        let mut out = Encoder::default();
+        out.u8(STATE_ENCODING_VERSION);
+        out.u32(self.markets.len() as u32);
+        for (id, market) in &self.markets {
+            out.string(id);
+            out.bytes(&market.commitment());
+        }
+        out.bytes(&self.trade_chain);
+        encode_balances(&mut out, &self.balances);
+        out.0
+    }
+}
+
+/// Keccak-256 of the encoding of a batch of actions described in the module docs. This is what
+/// `PublicValuesStruct` carries as `actionsHash`.
+pub fn actions_hash(actions: &[Action]) -> [u8; 32] {
+    alloy_primitives::keccak256(encode_actions(actions)).0
+}
+
+fn encode_actions(actions: &[Action]) -> Vec<u8> {
+    let mut out = Encoder::default();
+    out.u32(actions.len() as u32);
+    for action in actions {
+        match action {
+            Action::Place(order) => {
+                out.u8(0);
+                encode_order(&mut out, order);
+            }
+            Action::Cancel { id } => {
+                out.u8(1);
+                out.string(id);
+            }
+            Action::CancelAll { address } => {
+                out.u8(2);
+                out.string(address);
+            }
+            Action::Amend {
+                id,
+                price,
+                quantity,
+            } => {
+                out.u8(3);
+                out.string(id);
+                out.option(*price, Encoder::price);
+                out.option(*quantity, Encoder::quantity);
+            }
+            Action::Deposit {
+                address,
+                token,
+                amount,
+            }
+            | Action::Withdraw {
+                address,
+                token,
+                amount,
+            } => {
+                out.u8(if matches!(action, Action::Deposit { .. }) {
+                    4
+                } else {
+                    5
+                });
+                out.string(address);
+                out.string(token);
+                out.u128(*amount);
+            }
+        }
+    }
+    out.0
+}
+
+/// Keccak-256 of the encoding of `events` described in the module docs, which the guest commits
+/// so that an event stream received off-chain can be checked against the proof.
+pub fn event_hash(events: &[OrderEvent]) -> [u8; 32] {
+    let mut out = Encoder::default();
+    out.u32(events.len() as u32);
+    for event in events {
+        encode_event(&mut out, event);
+    }
+    alloy_primitives::keccak256(out.0).0
+}
+
+/// Extends the trade hash chain `head` with `trades`: each trade replaces the head with the
+/// Keccak-256 of the head followed by the trade's encoding described in the module docs. Starting
+/// from a known head, a consumer can check a batch's trades against the chain in the resulting
+/// state.
+pub fn chain_trades(head: [u8; 32], trades: &[Trade]) -> [u8; 32] {
+    trades.iter().fold(head, |head, trade| {
+        let mut out = Encoder::default();
+        out.bytes(&head);
+        encode_trade(&mut out, trade);
+        alloy_primitives::keccak256(out.0).0
+    })
+}
+
+/// Keccak-256 of the encoding of a batch's auction clearings described in the module docs, which
+/// the guest commits alongside the events.
+pub fn clearings_hash(clearings: &[Clearing]) -> [u8; 32] {
+    let mut out = Encoder::default();
+    out.u32(clearings.len() as u32);
+    for clearing in clearings {
+        out.string(&clearing.market);
+        out.price(clearing.price);
+        out.quantity(clearing.volume);
+    }
+    alloy_primitives::keccak256(out.0).0
+}
+
+/// The event's tag and then its fields in declaration order: order id, trader and the quantities
+/// or price it carries. A reject reason is a `u8`, `0` invalid followed by the error, `1` post-only
+/// or `2` fill-or-kill (see `encode_error`). A prevented self-trade is the address, the mode as
+/// `u8`, the taker and maker order ids and the quantities cancelled from each.
+fn encode_event(out: &mut Encoder, event: &OrderEvent) {
+    match event {
+        OrderEvent::Accepted {
+            order_id,
+            trader,
+            quantity,
+        } => {
+            out.u8(0);
+            out.string(order_id);
+            out.string(trader);
+            out.quantity(*quantity);
+        }
+        OrderEvent::Rested {
+            order_id,
+            trader,
+            price,
+            quantity,
+        } => {
+            out.u8(1);
+            out.string(order_id);
+            out.string(trader);
+            out.price(*price);
+            out.quantity(*quantity);
+        }
+        OrderEvent::PartiallyFilled {
+            order_id,
+            trader,
+            filled,
+            remaining,
+        } => {
+            out.u8(2);
+            out.string(order_id);
+            out.string(trader);
+            out.quantity(*filled);
+            out.quantity(*remaining);
+        }
+        OrderEvent::Filled {
+            order_id,
+            trader,
+            filled,
+        } => {
+            out.u8(3);
+            out.string(order_id);
+            out.string(trader);
+            out.quantity(*filled);
+        }
+        OrderEvent::Cancelled {
+            order_id,
+            trader,
+            cancelled,
+            remaining,
+        } => {
+            out.u8(4);
+            out.string(order_id);
+            out.string(trader);
+            out.quantity(*cancelled);
+            out.quantity(*remaining);
+        }
+        OrderEvent::Rejected {
+            order_id,
+            trader,
+            quantity,
+            reason,
+        } => {
+            out.u8(5);
+            out.string(order_id);
+            out.string(trader);
+            out.quantity(*quantity);
+            match reason {
+                RejectReason::Invalid(err) => {
+                    out.u8(0);
+                    encode_error(out, err);
+                }
+                RejectReason::PostOnly => out.u8(1),
+                RejectReason::FillOrKill => out.u8(2),
+            }
+        }
+        OrderEvent::Expired {
+            order_id,
+            trader,
+            quantity,
+        } => {
+            out.u8(6);
+            out.string(order_id);
+            out.string(trader);
+            out.quantity(*quantity);
+        }
+        OrderEvent::SelfTradePrevented(prevented) => {
+            out.u8(7);
+            out.string(&prevented.address);
+            out.u8(self_trade_prevention_tag(prevented.mode));
+            out.string(&prevented.taker_order_id);
+            out.string(&prevented.maker_order_id);
+            out.quantity(prevented.taker_cancelled);
+            out.quantity(prevented.maker_cancelled);
+        }
+    }
+}
+
+/// A `u8` tag, the variant's position in `MatchError` counting from `0`, followed by its fields:
+/// ids, addresses and tokens as strings, prices and quantities as above and amounts as `u128`.
+fn encode_error(out: &mut Encoder, err: &MatchError) {
+    match err {
+        MatchError::EmptyOrderId => out.u8(0),
+        MatchError::DuplicateOrderId(id) => {
+            out.u8(1);
+            out.string(id);
+        }
+        MatchError::UnknownOrderId(id) => {
+            out.u8(2);
+            out.string(id);
+        }
+        MatchError::InvalidAddress(address) => {
+            out.u8(3);
+            out.string(address);
+        }
+        MatchError::ZeroQuantity => out.u8(4),
+        MatchError::ZeroPrice => out.u8(5),
+        MatchError::AlreadyExpired => out.u8(6),
+        MatchError::TooPrecise(quantity) => {
+            out.u8(7);
+            out.quantity(*quantity);
+        }
+        MatchError::PriceTooPrecise(price) => {
+            out.u8(8);
+            out.price(*price);
+        }
+        MatchError::OutOfRange => out.u8(9),
+        MatchError::OffTick(price) => {
+            out.u8(10);
+            out.price(*price);
+        }
+        MatchError::OffLot(quantity) => {
+            out.u8(11);
+            out.quantity(*quantity);
+        }
+        MatchError::BelowMinQuantity(quantity) => {
+            out.u8(12);
+            out.quantity(*quantity);
+        }
+        MatchError::BelowMinNotional { notional, minimum } => {
+            out.u8(13);
+            out.u128(*notional);
+            out.u128(*minimum);
+        }
+        MatchError::UnknownToken(token) => {
+            out.u8(14);
+            out.string(token);
+        }
+        MatchError::InsufficientBalance {
+            token,
+            required,
+            available,
+        } => {
+            out.u8(15);
+            out.string(token);
+            out.u128(*required);
+            out.u128(*available);
+        }
+        MatchError::BalanceOverflow => out.u8(16),
+        MatchError::UnknownMarket(market) => {
+            out.u8(17);
+            out.string(market);
+        }
+        MatchError::UnsupportedInAuction => out.u8(18),
+    }
+}
+
+fn encode_trade(out: &mut Encoder, trade: &Trade) {
+    out.u64(trade.id);
+    encode_order(out, &trade.ask_order);
+    encode_order(out, &trade.bid_order);
+    out.price(trade.price);
+    out.quantity(trade.quantity);
+    out.u8(order_type_tag(trade.taker_side));
+    out.u128(trade.maker_fee);
+    out.u128(trade.taker_fee);
+}
+
+fn encode_balances(out: &mut Encoder, balances: &Balances) {
+    out.u32(balances.iter().count() as u32);
+    for (trader, token, balance) in balances.iter() {
+        out.string(trader);
+        out.string(token);
+        out.u128(balance.available);
+        out.u128(balance.locked);
+    }
+}
+
+/// Self-trade prevention mode as `u8`, fee schedule (default rates, `u32` tier count, each tier
+/// as address then rates in address order, collector), price rule as `u8`, the base and quote
+/// tokens, the price and quantity decimals as `u8`, then the option of a tick size, the option of
+/// a lot size, the minimum quantity and the minimum notional as `u128`, then the matching mode as
+/// `u8` (`0` continuous, `1` batch auction) and the allocation (`0` price-time, or `1` pro-rata
+/// followed by its top-order priority as `u8`).
+/// Rates are the maker then taker basis points as `u32`; a token is its address followed by its
+/// decimals as `u8`.
+fn encode_config(out: &mut Encoder, config: &MarketConfig) {
+    out.u8(self_trade_prevention_tag(config.self_trade_prevention));
+    let rates = |out: &mut Encoder, rates: &FeeRates| {
+        out.u32(rates.maker_bps);
+        out.u32(rates.taker_bps);
+    };
+    rates(out, &config.fees.default);
+    out.u32(config.fees.tiers.len() as u32);
+    for (address, tier) in &config.fees.tiers {
+        out.string(address);
+        rates(out, tier);
+    }
+    out.string(&config.fees.collector);
+    out.u8(match config.price_rule {
+        PriceRule::Maker => 0,
+        PriceRule::Taker => 1,
+        PriceRule::Midpoint => 2,
+    });
+    for token in [&config.base, &config.quote] {
+        out.string(&token.address);
+        out.u8(token.decimals);
+    }
+    out.u8(config.price_decimals);
+    out.u8(config.quantity_decimals);
+    out.option(config.tick_size, Encoder::price);
+    out.option(config.lot_size, Encoder::quantity);
+    out.quantity(config.min_quantity);
+    out.u128(config.min_notional);
+    out.u8(match config.matching_mode {
+        MatchingMode::Continuous => 0,
+        MatchingMode::BatchAuction => 1,
+    });
+    match config.allocation {
+        Allocation::PriceTime => out.u8(0),
+        Allocation::ProRata(ProRata { top_order_priority }) => {
+            out.u8(1);
+            out.u8(top_order_priority as u8);
+        }
+    }
+}
+
+/// Fields in declaration order. Order type is `0` for a bid and `1` for an ask; time in force is a
+/// `u8` tag (`0` good-til-cancelled, `1` immediate-or-cancel, `2` fill-or-kill, `3` good-til-time
+/// followed by its `u64` expiry); post-only is `0` reject or `1` reprice.
+fn encode_order(out: &mut Encoder, order: &Order) {
+    out.string(&order.id);
+    out.string(&order.address);
+    out.string(&order.market);
+    out.u8(order_type_tag(order.order_type));
+    out.option(order.price, Encoder::price);
+    out.quantity(order.quantity);
+    out.option(order.max_slippage_bps, Encoder::u32);
+    match order.time_in_force {
+        TimeInForce::GoodTilCancelled => out.u8(0),
+        TimeInForce::ImmediateOrCancel => out.u8(1),
+        TimeInForce::FillOrKill => out.u8(2),
+        TimeInForce::GoodTilTime(expires_at) => {
+            out.u8(3);
+            out.u64(expires_at);
+        }
+    }
+    out.option(order.post_only, |out, post_only| {
+        out.u8(match post_only {
+            PostOnly::Reject => 0,
+            PostOnly::Reprice => 1,
+        })
+    });
+    out.option(order.self_trade_prevention, |out, mode| {
+        out.u8(self_trade_prevention_tag(mode))
+    });
+    out.option(order.trigger, Encoder::price);
+    out.option(order.display_quantity, Encoder::quantity);
+    out.quantity(order.hidden_quantity);
+    out.u64(order.sequence);
+    out.u128(order.locked);
+}
+
+/// The `uint8` an order type is given here and in `PublicValuesStruct.orderTypes`.
+pub(crate) fn order_type_tag(order_type: OrderType) -> u8 {
+    match order_type {
+        OrderType::Bid => 0,
+        OrderType::Ask => 1,
+    }
+}
+
+fn self_trade_prevention_tag(mode: SelfTradePrevention) -> u8 {
+    match mode {
+        SelfTradePrevention::CancelNewest => 0,
+        SelfTradePrevention::CancelOldest => 1,
+        SelfTradePrevention::CancelBoth => 2,
+        SelfTradePrevention::DecrementAndCancel => 3,
+    }
+}
+
+#[derive(Default)]
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn u128(&mut self, value: u128) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.bytes(value.as_bytes());
+    }
+
+    fn price(&mut self, price: Price) {
+        let price = price.normalized();
+        self.u64(price.ticks);
+        self.u8(price.scale);
+    }
+
+    fn quantity(&mut self, quantity: Quantity) {
+        let quantity = quantity.normalized();
+        self.u64(quantity.lots);
+        self.u8(quantity.scale);
+    }
+
+    fn option<T>(&mut self, value: Option<T>, encode: impl FnOnce(&mut Self, T)) {
+        match value {
+            None => self.u8(0),
+            Some(value) => {
+                self.u8(1);
+                encode(self, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{match_order, MatchContext, PriceTime};
+
+    // Test vectors for other implementations of the encoding. Changing any of them means the
+    // encoding changed and `STATE_ENCODING_VERSION` must be bumped.
+
+    /// `MarketConfig::default()`.
+    #[rustfmt::skip]
+    const DEFAULT_CONFIG: &str = concat!(
+        "00",                       // cancel-newest
+        "00000000", "00000000",     // default maker and taker bps
+        "00000000",                 // no fee tiers
+        "00000000",                 // empty collector
+        "00",                       // maker price rule
+        "00000004", "42415345", "12",   // base token "BASE", 18 decimals
+        "00000005", "51554f5445", "12", // quote token "QUOTE", 18 decimals
+        "08", "08",                 // prices and quantities at 8 decimals
+        "00", "00",                 // no tick or lot size
+        "0000000000000000", "00",   // no minimum quantity
+        "00000000000000000000000000000000", // no minimum notional
+        "00",                       // continuous matching
+        "00",                       // price-time allocation
+    );
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_vector_empty_state() {
+        let state = State::new();
+        let expected = [
+            "08",                       // version
+            DEFAULT_CONFIG,
+            "00000000", "00000000",     // no bids, no asks
+            "0000000000000000000000000000000000000000000000000000000000000000", // trade chain
+            "00000000000000000000000000000000", // collected fees
+            "00",                       // no last price
+            "00000000",                 // no stop orders
+            "0000000000000000",         // sequence
+            "00000000",                 // no balances
+        ];
+        assert_eq!(hex(&state.canonical_encoding()), expected.concat());
+        assert_eq!(hex(&state.commitment()), EMPTY_STATE_COMMITMENT);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_vector_one_resting_bid() {
+        let mut state = State::new();
+        let address = "0x1111111111111111111111111111111111111111";
+        state.balances.deposit(address, "QUOTE", 11 * 10u128.pow(18)).unwrap();
+        let price = Price::new(1050, 3);
+        let bid = Order::limit("1", address, OrderType::Bid, price, Quantity::new(10, 0));
+        match_order(&mut state, bid, &MatchContext::default(), &PriceTime).unwrap();
+
+        let encoding = hex(&state.canonical_encoding());
+        let bid = concat!(
+            "00000001", "31",               // id "1"
+            "0000002a", "3078",             // address: "0x" then forty "1"s
+            "31313131313131313131313131313131313131313131313131313131313131313131313131313131",
+            "00000000",                     // no market
+            "00",                           // bid
+            "01", "0000000000000069", "02", // price 1.050 encodes as 105 at scale 2
+            "000000000000000a", "00",       // quantity 10
+            "00",                           // no slippage cap
+            "00",                           // good-til-cancelled
+            "00", "00", "00", "00",         // no post-only, STP override, trigger or display
+            "0000000000000000", "00",       // no hidden quantity
+            "0000000000000001",             // sequence
+            "000000000000000091b77e5e5d9a0000", // locks 10.5 QUOTE at 18 decimals
+        );
+        let balances = concat!(
+            "00000001",                     // one balance
+            "0000002a", "3078",             // the same trader
+            "31313131313131313131313131313131313131313131313131313131313131313131313131313131",
+            "00000005", "51554f5445",       // token "QUOTE"
+            "000000000000000006f05b59d3b20000", // 0.5 available
+            "000000000000000091b77e5e5d9a0000", // 10.5 locked
+        );
+        let expected = [
+            "08",                       // version
+            DEFAULT_CONFIG,
+            "00000001", bid,            // one bid
+            "00000000",                 // no asks
+            "0000000000000000000000000000000000000000000000000000000000000000", // trade chain
+            "00000000000000000000000000000000", // collected fees
+            "00",                       // no last price
+            "00000000",                 // no stop orders
+            "0000000000000001",         // sequence
+            balances,
+        ];
+        assert_eq!(encoding, expected.concat());
+        assert_eq!(hex(&state.commitment()), ONE_BID_COMMITMENT);
+    }
+
+    #[test]
+    fn test_equal_states_share_a_commitment() {
+        let address = "0x1111111111111111111111111111111111111111";
+        let mut a = State::new();
+        let mut b = State::new();
+        let bid = |price| Order::limit("1", address, OrderType::Bid, price, Quantity::new(10, 0));
+        for state in [&mut a, &mut b] {
+            state
+                .balances
+                .deposit(address, "QUOTE", 11 * 10u128.pow(18))
+                .unwrap();
+        }
+        match_order(
+            &mut a,
+            bid(Price::new(105, 2)),
+            &MatchContext::default(),
+            &PriceTime,
+        )
+        .unwrap();
+        match_order(
+            &mut b,
+            bid(Price::new(1050, 3)),
+            &MatchContext::default(),
+            &PriceTime,
+        )
+        .unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.commitment(), b.commitment());
+        assert_ne!(a.commitment(), State::new().commitment());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_vector_exchange() {
+        let mut exchange = Exchange::new();
+        assert_eq!(
+            hex(&exchange.canonical_encoding()),
+            concat!(
+                "08",                       // version
+                "00000000",                 // no markets
+                "0000000000000000000000000000000000000000000000000000000000000000", // trade chain
+                "00000000",                 // no balances
+            )
+        );
+
+        // Each market shows up as its id and the commitment of its state
+        exchange.add_market("X", MarketConfig::default());
+        let expected = [
+            "08",                       // version
+            "00000001",                 // one market
+            "00000001", "58",           // id "X"
+            EMPTY_STATE_COMMITMENT,
+            "0000000000000000000000000000000000000000000000000000000000000000", // trade chain
+            "00000000",                 // no balances
+        ];
+        assert_eq!(hex(&exchange.canonical_encoding()), expected.concat());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_vector_actions() {
+        let address = "0x1111111111111111111111111111111111111111";
+        let actions = [
+            Action::Deposit { address: address.to_string(), token: "QUOTE".to_string(), amount: 5 },
+            Action::Cancel { id: "1".to_string() },
+        ];
+        assert_eq!(
+            hex(&encode_actions(&actions)),
+            concat!(
+                "00000002",                 // two actions
+                "04",                       // deposit
+                "0000002a", "3078",         // address: "0x" then forty "1"s
+                "31313131313131313131313131313131313131313131313131313131313131313131313131313131",
+                "00000005", "51554f5445",   // token "QUOTE"
+                "00000000000000000000000000000005", // amount
+                "01", "00000001", "31",     // cancel "1"
+            )
+        );
+        assert_eq!(actions_hash(&actions), alloy_primitives::keccak256(encode_actions(&actions)).0);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn test_vector_batch_output() {
+        let keccak = |encoding: &str| {
+            alloy_primitives::keccak256(alloy_primitives::hex::decode(encoding).unwrap()).0
+        };
+        let events = [
+            OrderEvent::Filled {
+                order_id: "1".to_string(),
+                trader: "t".to_string(),
+                filled: Quantity::new(50, 1),
+            },
+            OrderEvent::Rejected {
+                order_id: "2".to_string(),
+                trader: "t".to_string(),
+                quantity: Quantity::new(1, 0),
+                reason: RejectReason::Invalid(MatchError::UnknownMarket("M".to_string())),
+            },
+        ];
+        let encoding = concat!(
+            "00000002",                     // two events
+            "03", "00000001", "31",         // filled, order "1"
+            "00000001", "74",               // trader "t"
+            "0000000000000005", "00",       // 5.0 encodes as 5
+            "05", "00000001", "32",         // rejected, order "2"
+            "00000001", "74",               // trader "t"
+            "0000000000000001", "00",       // quantity 1
+            "00", "11", "00000001", "4d",   // invalid: unknown market "M"
+        );
+        assert_eq!(event_hash(&events), keccak(encoding));
+
+        let clearings = [Clearing {
+            market: "M".to_string(),
+            price: Price::new(105, 2),
+            volume: Quantity::new(3, 0),
+        }];
+        let encoding = concat!(
+            "00000001",                     // one clearing
+            "00000001", "4d",               // market "M"
+            "0000000000000069", "02",       // price 1.05
+            "0000000000000003", "00",       // volume 3
+        );
+        assert_eq!(clearings_hash(&clearings), keccak(encoding));
+
+        let order = |id: &str, order_type| {
+            Order::limit(id, "t", order_type, Price::new(1, 0), Quantity::new(2, 0))
+        };
+        let trade = Trade {
+            id: 7,
+            ask_order: order("a", OrderType::Ask),
+            bid_order: order("b", OrderType::Bid),
+            price: Price::new(1, 0),
+            quantity: Quantity::new(2, 0),
+            taker_side: OrderType::Bid,
+            maker_fee: 0,
+            taker_fee: 1,
+        };
+        let mut encoding = hex(&[0; 32]);   // the head
+        encoding.push_str("0000000000000007"); // id
+        for order in [&trade.ask_order, &trade.bid_order] {
+            let mut out = Encoder::default();
+            encode_order(&mut out, order);
+            encoding.push_str(&hex(&out.0));
+        }
+        encoding.push_str(concat!(
+            "0000000000000001", "00",       // price 1
+            "0000000000000002", "00",       // quantity 2
+            "00",                           // a bid took liquidity
+            "00000000000000000000000000000000", // maker fee
+            "00000000000000000000000000000001", // taker fee
+        ));
+        assert_eq!(chain_trades([0; 32], &[trade]), keccak(&encoding));
+    }
+
+    const EMPTY_STATE_COMMITMENT: &str =
+        "1da6bf0e22dae5f4630c374d6bc9d59aeafd2328902ebd2da8ec0e9a7ea1d138";
+    const ONE_BID_COMMITMENT: &str =
+        "dc8106c83d4fa3537f59c9be6b02abb20e81f59f9b5b6349c6299d304cf0d0e7";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
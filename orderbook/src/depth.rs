@@ -0,0 +1,80 @@
+use crate::{BookSide, Price, Quantity};
+use serde::{Deserialize, Serialize};
+
+/// Total displayed quantity at one price, saturating at the largest quantity its scale can hold.
+/// Hidden iceberg reserves are not included.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct DepthLevel {
+    pub price: Price,
+    pub quantity: Quantity,
+}
+
+/// An aggregated (L2) view of the top of the book, best price first on each side.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash, Default)]
+pub struct Depth {
+    pub bids: Vec<DepthLevel>,
+    pub asks: Vec<DepthLevel>,
+}
+
+impl Depth {
+    pub(crate) fn new(bids: &BookSide, asks: &BookSide, levels: usize) -> Self {
+        Self {
+            bids: aggregate(bids, levels),
+            asks: aggregate(asks, levels),
+        }
+    }
+
+    /// CRC32 over the levels in the style of exchange book checksums: `price:quantity` pairs
+    /// alternating bid and ask from the top down (`bid1:ask1:bid2:ask2...`, skipping a side once it
+    /// runs out), joined with `:`. Values are written without trailing zeros, so the checksum does
+    /// not depend on the scale a price was submitted at.
+    pub fn checksum(&self) -> u32 {
+        let mut fields = vec![];
+        for i in 0..self.bids.len().max(self.asks.len()) {
+            for level in [self.bids.get(i), self.asks.get(i)].into_iter().flatten() {
+                fields.push(format!(
+                    "{}:{}",
+                    level.price.normalized(),
+                    level.quantity.normalized()
+                ));
+            }
+        }
+        crc32(fields.join(":").as_bytes())
+    }
+}
+
+fn aggregate(side: &BookSide, levels: usize) -> Vec<DepthLevel> {
+    side.levels()
+        .take(levels)
+        .map(|(price, level)| DepthLevel {
+            price: *price,
+            quantity: level.values().fold(Quantity::zero(0), |total, order| {
+                total.saturating_add(order.quantity)
+            }),
+        })
+        .collect()
+}
+
+/// CRC-32 (IEEE 802.3), the variant exchanges use for book checksums.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{
+    apply_action, batch_values, chain_trades, clear_auction, Action, ActionReport, Balances,
+    BatchOutput, Clearing, MarketConfig, MatchContext, MatchError, MatchingMode, Order, OrderEvent,
+    PublicValuesStruct, RejectReason, State,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Many markets, each a `State` of its own, that share one set of balances. Orders go to the
+/// market named by `Order::market`, and order ids are unique across markets so that cancels and
+/// amends can find their market by id.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash, Default)]
+pub struct Exchange {
+    /// Markets by id. Their own `balances` stay empty; the shared ones below stand in for them
+    /// while a market handles an action.
+    pub markets: BTreeMap<String, State>,
+    pub balances: Balances,
+    /// Running commitment to every trade in every market, in the order they happened. Trade ids
+    /// are only unique within a market, so a trade here is known by its orders' market and its id.
+    pub trade_chain: [u8; 32],
+}
+
+impl Exchange {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a market with an empty book, replacing any market with the same id.
+    pub fn add_market(&mut self, id: &str, config: MarketConfig) {
+        self.markets
+            .insert(id.to_string(), State::with_config(config));
+    }
+
+    pub fn market(&self, id: &str) -> Option<&State> {
+        self.markets.get(id)
+    }
+
+    /// Root of the Merkle tree over every balance, as `State::balance_root`.
+    pub fn balance_root(&self) -> [u8; 32] {
+        self.balances.root()
+    }
+
+    /// Routes one action to its market, or to every market for `CancelAll`, and extends
+    /// `trade_chain` with its trades. On error nothing is changed.
+    pub fn apply_action(
+        &mut self,
+        action: Action,
+        ctx: &MatchContext,
+    ) -> Result<ActionReport, MatchError> {
+        let markets = self.route(&action)?;
+        let mut report = ActionReport::default();
+        for market in markets {
+            let part = self.in_market(&market, |state| apply_action(state, action.clone(), ctx))?;
+            report.post_only = report.post_only.or(part.post_only);
+            report.self_trades.extend(part.self_trades);
+            report.triggered_stops.extend(part.triggered_stops);
+            report.events.extend(part.events);
+            report.trades.extend(part.trades);
+        }
+        self.trade_chain = chain_trades(self.trade_chain, &report.trades);
+        Ok(report)
+    }
+
+    /// Applies `actions` in order across markets, as `apply_actions` does for one market, then
+    /// runs the auction of every market in batch auction mode, in id order.
+    pub fn apply_actions(&mut self, actions: &[Action], ctx: &MatchContext) -> BatchOutput {
+        let mut output = BatchOutput::default();
+        self.balances.take_updates();
+        for action in actions {
+            match self.apply_action(action.clone(), ctx) {
+                Ok(report) => {
+                    output.events.extend(report.events);
+                    output.trades.extend(report.trades);
+                }
+                Err(err) => {
+                    if let Action::Place(order) = action {
+                        output
+                            .events
+                            .push(OrderEvent::rejected(order, RejectReason::Invalid(err)));
+                    }
+                }
+            }
+        }
+        let auctions: Vec<String> = self
+            .markets
+            .iter()
+            .filter(|(_, state)| state.config.matching_mode == MatchingMode::BatchAuction)
+            .map(|(market, _)| market.clone())
+            .collect();
+        for market in auctions {
+            let (clearing, report) = self.in_market(&market, |state| clear_auction(state, ctx));
+            self.trade_chain = chain_trades(self.trade_chain, &report.trades);
+            output.events.extend(report.events);
+            output.trades.extend(report.trades);
+            output
+                .clearings
+                .extend(clearing.map(|clearing| Clearing { market, ..clearing }));
+        }
+        output.balance_updates = self.balances.take_updates();
+        output
+    }
+
+    /// The markets an action goes to. A deposit or withdrawal goes to the first market that
+    /// trades the token; with shared balances any of them would do.
+    fn route(&self, action: &Action) -> Result<Vec<String>, MatchError> {
+        let market = match action {
+            Action::Place(order) => {
+                if !self.markets.contains_key(&order.market) {
+                    return Err(MatchError::UnknownMarket(order.market.clone()));
+                }
+                let elsewhere = |(id, state): (&String, &State)| {
+                    *id != order.market && state.order(&order.id).is_some()
+                };
+                if self.markets.iter().any(elsewhere) {
+                    return Err(MatchError::DuplicateOrderId(order.id.clone()));
+                }
+                order.market.clone()
+            }
+            Action::Cancel { id } | Action::Amend { id, .. } => self
+                .markets
+                .iter()
+                .find(|(_, state)| state.order(id).is_some())
+                .map(|(market, _)| market.clone())
+                .ok_or(MatchError::UnknownOrderId(id.clone()))?,
+            Action::CancelAll { .. } => return Ok(self.markets.keys().cloned().collect()),
+            Action::Deposit { token, .. } | Action::Withdraw { token, .. } => self
+                .markets
+                .iter()
+                .find(|(_, state)| {
+                    [&state.config.base, &state.config.quote]
+                        .iter()
+                        .any(|t| t.address == *token)
+                })
+                .map(|(market, _)| market.clone())
+                .ok_or(MatchError::UnknownToken(token.clone()))?,
+        };
+        Ok(vec![market])
+    }
+
+    /// Runs `f` on a market with the shared balances swapped in.
+    fn in_market<T>(&mut self, market: &str, f: impl FnOnce(&mut State) -> T) -> T {
+        let state = self
+            .markets
+            .get_mut(market)
+            .expect("actions are only routed to existing markets");
+        std::mem::swap(&mut state.balances, &mut self.balances);
+        let result = f(state);
+        std::mem::swap(&mut state.balances, &mut self.balances);
+        result
+    }
+}
+
+/// The public values for a batch of `actions`, run at `ctx`, that took `prev` to `next`, as
+/// `public_values` gives them for a single market. The state fields are `Exchange::commitment`s.
+pub fn exchange_public_values(
+    prev: &Exchange,
+    actions: &[Action],
+    ctx: &MatchContext,
+    next: &Exchange,
+    output: &BatchOutput,
+) -> PublicValuesStruct {
+    let config = |order: &Order| next.market(&order.market).map(|state| &state.config);
+    let mut values = batch_values(actions, ctx, output, config);
+    values.prevState = prev.commitment().into();
+    values.newState = next.commitment().into();
+    values.tradeChain = next.trade_chain.into();
+    values.balanceRoot = next.balance_root().into();
+    values
+}
@@ -0,0 +1,370 @@
+use alloy_primitives::U256;
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, AddAssign, Sub, SubAssign};
+use std::str::FromStr;
+
+/// Largest supported number of decimal places. Keeps `units * 10^scale` inside a `u128`.
+pub const MAX_SCALE: u8 = 18;
+
+fn pow10(exp: u8) -> u128 {
+    10u128.pow(exp as u32)
+}
+
+macro_rules! fixed_point {
+    ($(#[$meta:meta])* $name:ident, $units:ident) => {
+        $(#[$meta])*
+        #[derive(Serialize, Debug, Clone, Copy, Default)]
+        pub struct $name {
+            pub $units: u64,
+            pub scale: u8,
+        }
+
+        impl $name {
+            pub fn new($units: u64, scale: u8) -> Self {
+                assert!(scale <= MAX_SCALE, "scale {} exceeds {}", scale, MAX_SCALE);
+                Self { $units, scale }
+            }
+
+            pub fn zero(scale: u8) -> Self {
+                Self::new(0, scale)
+            }
+
+            pub fn is_zero(&self) -> bool {
+                self.$units == 0
+            }
+
+            /// Re-expresses the value with `scale` decimal places, or `None` if that would lose
+            /// precision or overflow.
+            pub fn to_scale(self, scale: u8) -> Option<Self> {
+                if scale > MAX_SCALE {
+                    return None;
+                }
+                let $units = if scale >= self.scale {
+                    self.$units.checked_mul(10u64.checked_pow((scale - self.scale) as u32)?)?
+                } else {
+                    let divisor = 10u64.checked_pow((self.scale - scale) as u32)?;
+                    if self.$units % divisor != 0 {
+                        return None;
+                    }
+                    self.$units / divisor
+                };
+                Some(Self { $units, scale })
+            }
+
+            // Strips trailing zeros so that equal values share one representation.
+            pub(crate) fn normalized(self) -> Self {
+                let mut value = self;
+                while value.scale > 0 && value.$units % 10 == 0 {
+                    value.$units /= 10;
+                    value.scale -= 1;
+                }
+                if value.$units == 0 {
+                    value.scale = 0;
+                }
+                value
+            }
+
+            /// The value as a whole number of units at `scale`, which is how it appears in
+            /// `PublicValuesStruct` at the market's scale. Values that compare equal give the same
+            /// number. `None` if the value has more decimal places than `scale`.
+            pub fn to_u256(self, scale: u8) -> Option<U256> {
+                let value = self.normalized();
+                if value.scale > scale || scale > MAX_SCALE {
+                    return None;
+                }
+                let factor = U256::from(10u8).pow(U256::from(scale - value.scale));
+                Some(U256::from(value.$units) * factor)
+            }
+
+            pub fn checked_add(self, other: Self) -> Option<Self> {
+                let scale = self.scale.max(other.scale);
+                let (a, b) = (self.to_scale(scale)?, other.to_scale(scale)?);
+                Some(Self { $units: a.$units.checked_add(b.$units)?, scale })
+            }
+
+            /// The sum, or the largest value at the larger of the two scales if it does not fit.
+            pub fn saturating_add(self, other: Self) -> Self {
+                let scale = self.scale.max(other.scale);
+                self.checked_add(other).unwrap_or(Self { $units: u64::MAX, scale })
+            }
+
+            pub fn checked_sub(self, other: Self) -> Option<Self> {
+                let scale = self.scale.max(other.scale);
+                let (a, b) = (self.to_scale(scale)?, other.to_scale(scale)?);
+                Some(Self { $units: a.$units.checked_sub(b.$units)?, scale })
+            }
+
+            /// Whether the value is a whole number of `step`s. A zero step allows any value.
+            pub fn is_multiple_of(self, step: Self) -> bool {
+                let (units, step) = Self::aligned(self, step);
+                step == 0 || units % step == 0
+            }
+
+            /// The largest whole number of `step`s that does not exceed the value, at the larger
+            /// of the two scales. A zero step leaves the value as it is.
+            pub fn round_down_to(self, step: Self) -> Self {
+                let (units, step_units) = Self::aligned(self, step);
+                if step_units == 0 {
+                    return self;
+                }
+                let scale = self.scale.max(step.scale);
+                // Never larger than the value itself, which fits at this scale
+                Self { $units: (units - units % step_units) as u64, scale }
+            }
+
+            /// The value in units of `scale`, which must be at least its own scale.
+            pub fn units_at(self, scale: u8) -> u128 {
+                self.$units as u128 * pow10(scale - self.scale)
+            }
+
+            /// `units` at `scale`, without trailing zeros, or `None` if that does not fit.
+            pub fn from_units(units: u128, scale: u8) -> Option<Self> {
+                let (mut units, mut scale) = (units, scale);
+                while scale > 0 && units % 10 == 0 {
+                    units /= 10;
+                    scale -= 1;
+                }
+                Some(Self {
+                    $units: units.try_into().ok()?,
+                    scale: if units == 0 { 0 } else { scale },
+                })
+            }
+
+            // Both values in units of the larger scale, which cannot overflow a u128
+            fn aligned(a: Self, b: Self) -> (u128, u128) {
+                let scale = a.scale.max(b.scale);
+                (a.units_at(scale), b.units_at(scale))
+            }
+        }
+
+        // Checks the scale, which every comparison relies on being at most `MAX_SCALE`
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                #[derive(Deserialize)]
+                struct Raw {
+                    $units: u64,
+                    scale: u8,
+                }
+                let Raw { $units, scale } = Raw::deserialize(deserializer)?;
+                if scale > MAX_SCALE {
+                    return Err(D::Error::custom(format!("scale {} exceeds {}", scale, MAX_SCALE)));
+                }
+                Ok(Self { $units, scale })
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.cmp(other) == Ordering::Equal
+            }
+        }
+
+        impl Eq for $name {}
+
+        impl Ord for $name {
+            fn cmp(&self, other: &Self) -> Ordering {
+                // Cross-multiplying by the other side's scale is exact in u128 for scales <= 18.
+                let lhs = self.$units as u128 * pow10(other.scale);
+                let rhs = other.$units as u128 * pow10(self.scale);
+                lhs.cmp(&rhs)
+            }
+        }
+
+        impl PartialOrd for $name {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl Hash for $name {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                let normalized = self.normalized();
+                normalized.$units.hash(state);
+                normalized.scale.hash(state);
+            }
+        }
+
+        impl Add for $name {
+            type Output = Self;
+
+            fn add(self, other: Self) -> Self {
+                self.checked_add(other).expect(concat!(stringify!($name), " overflow"))
+            }
+        }
+
+        impl AddAssign for $name {
+            fn add_assign(&mut self, other: Self) {
+                *self = *self + other;
+            }
+        }
+
+        impl Sub for $name {
+            type Output = Self;
+
+            fn sub(self, other: Self) -> Self {
+                self.checked_sub(other).expect(concat!(stringify!($name), " underflow"))
+            }
+        }
+
+        impl SubAssign for $name {
+            fn sub_assign(&mut self, other: Self) {
+                *self = *self - other;
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                if self.scale == 0 {
+                    return write!(f, "{}", self.$units);
+                }
+                let divisor = pow10(self.scale);
+                let units = self.$units as u128;
+                write!(
+                    f,
+                    "{}.{:0width$}",
+                    units / divisor,
+                    units % divisor,
+                    width = self.scale as usize
+                )
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            /// Parses a plain decimal such as `"1.05"`, keeping every written decimal place.
+            fn from_str(s: &str) -> Result<Self, String> {
+                let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+                let mut digits = whole.chars().chain(fraction.chars());
+                if whole.is_empty() || !digits.all(|c| c.is_ascii_digit()) {
+                    return Err(format!("Invalid decimal: {:?}", s));
+                }
+                if fraction.len() > MAX_SCALE as usize {
+                    return Err(format!("Too many decimal places: {:?}", s));
+                }
+                let $units = format!("{}{}", whole, fraction)
+                    .parse::<u64>()
+                    .map_err(|e| e.to_string())?;
+                Ok(Self { $units, scale: fraction.len() as u8 })
+            }
+        }
+    };
+}
+
+fixed_point!(
+    /// A limit price as an integer number of ticks with `scale` decimal places, so
+    /// `Price::new(105, 2)` is 1.05.
+    Price,
+    ticks
+);
+
+fixed_point!(
+    /// An order size as an integer number of lots with `scale` decimal places.
+    Quantity,
+    lots
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash_of<T: Hash>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_ordering_across_scales() {
+        // 1.05 written with two and three decimal places is the same price
+        assert_eq!(Price::new(105, 2), Price::new(1050, 3));
+        assert_eq!(hash_of(&Price::new(105, 2)), hash_of(&Price::new(1050, 3)));
+
+        assert!(Price::new(105, 2) < Price::new(1051, 3));
+        assert!(Price::new(2, 0) > Price::new(199, 2));
+        assert_eq!(Quantity::zero(0), Quantity::zero(6));
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        let price: Price = "1.05".parse().unwrap();
+        assert_eq!(price, Price::new(105, 2));
+        assert_eq!(price.to_string(), "1.05");
+        assert_eq!(Price::new(5, 3).to_string(), "0.005");
+        assert_eq!("1000".parse::<Quantity>().unwrap(), Quantity::new(1000, 0));
+
+        assert!(
+            "-1".parse::<Price>().is_err(),
+            "Negative prices are rejected"
+        );
+        assert!("NaN".parse::<Price>().is_err(), "NaN is rejected");
+        assert!(
+            "1.0.5".parse::<Price>().is_err(),
+            "Malformed decimals are rejected"
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_aligns_scales() {
+        let a = Quantity::new(15, 1);
+        let b = Quantity::new(25, 2);
+        assert_eq!(a + b, Quantity::new(175, 2));
+        assert_eq!(a - b, Quantity::new(125, 2));
+        assert_eq!(b.checked_sub(a), None, "Subtraction must not wrap");
+        assert_eq!(
+            Quantity::new(u64::MAX, 2).saturating_add(Quantity::new(1, 0)),
+            Quantity::new(u64::MAX, 2)
+        );
+        assert_eq!(
+            Quantity::new(20, 0).checked_add(Quantity::new(1, 18)),
+            None,
+            "20 does not fit at 18 places"
+        );
+        assert_eq!(Price::new(150, 2).to_scale(1), Some(Price::new(15, 1)));
+        assert_eq!(
+            Price::new(155, 2).to_scale(1),
+            None,
+            "Rescaling must be exact"
+        );
+        assert_eq!(Price::new(105, 2).to_u256(2), Some(U256::from(105u64)));
+        assert_eq!(Price::new(1050, 3).to_u256(2), Some(U256::from(105u64)));
+        assert_eq!(
+            Price::new(105, 2).to_u256(8),
+            Some(U256::from(105_000_000u64))
+        );
+        assert_eq!(
+            Price::new(1051, 3).to_u256(2),
+            None,
+            "Encoding must be exact"
+        );
+    }
+
+    #[test]
+    fn test_deserialize_checks_the_scale() {
+        let price: Price = serde_json::from_str(r#"{"ticks":105,"scale":2}"#).unwrap();
+        assert_eq!(price, Price::new(105, 2));
+        assert!(serde_json::from_str::<Price>(r#"{"ticks":1,"scale":40}"#).is_err());
+        assert!(serde_json::from_str::<Quantity>(r#"{"lots":1,"scale":19}"#).is_err());
+    }
+
+    #[test]
+    fn test_multiples_of_a_step() {
+        let tick = Price::new(5, 2);
+        assert!(Price::new(105, 2).is_multiple_of(tick));
+        assert!(Price::new(11, 1).is_multiple_of(tick));
+        assert!(!Price::new(1051, 3).is_multiple_of(tick));
+        assert!(Price::new(1051, 3).is_multiple_of(Price::zero(0)));
+
+        let lot = Quantity::new(25, 2);
+        assert_eq!(
+            Quantity::new(13, 1).round_down_to(lot),
+            Quantity::new(125, 2)
+        );
+        assert_eq!(Quantity::new(2, 1).round_down_to(lot), Quantity::zero(0));
+    }
+}
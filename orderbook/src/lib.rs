@@ -1,172 +1,3730 @@
 use alloy_sol_types::sol;
-use serde::{Serialize, Deserialize};
-use std::cmp::Ordering;
-use std::hash::{Hash, Hasher};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+mod auction;
+mod balance;
+mod book;
+mod commitment;
+mod depth;
+mod exchange;
+mod fixed;
+mod policy;
+
+pub use auction::{clear_auction, clearing_price, Clearing};
+pub use balance::{Balance, BalanceUpdate, Balances};
+pub use book::{BookSide, Level};
+pub use commitment::{
+    actions_hash, chain_trades, clearings_hash, event_hash, STATE_ENCODING_VERSION,
+};
+pub use depth::{Depth, DepthLevel};
+pub use exchange::{exchange_public_values, Exchange};
+pub use fixed::{Price, Quantity, MAX_SCALE};
+pub use policy::{Allocation, MatchingPolicy, PriceTime, ProRata};
 
 sol! {
     /// The public values encoded as a struct that can be easily deserialized inside Solidity.
+    /// `prevState` and `newState` are `State::commitment`s, or `Exchange::commitment`s for a
+    /// batch across markets; the arrays list the batch's orders, with prices and quantities as
+    /// whole units at their market's `price_decimals` and `quantity_decimals`. `actionsHash` is
+    /// `actions_hash` over every action of the batch, so that a verifier holding the actions it
+    /// accepted, deposits and withdrawals included, can check that nothing else was applied.
+    /// `timestamp` is the `MatchContext` clock the batch ran at, which decided what expired.
     struct PublicValuesStruct {
-        uint64 prevState;
+        bytes32 prevState;
+        bytes32 actionsHash;
+        uint64 timestamp;
         address[] traders;
+        string[] markets;
         uint8[] orderTypes;
         uint256[] price;
         uint256[] quantity;
-        uint64 newState;
+        bytes32 newState;
+        bytes32 eventsHash;
+        bytes32 tradeChain;
+        bytes32 balanceRoot;
+        bytes32 clearingsHash;
+    }
+}
+
+/// The public values for a batch of `actions`, run at `ctx`, that took `prev` to `next`. Every
+/// placed order is listed, rejected ones included; a market order has price zero, and an address
+/// that does not parse shows as the zero address. A price or quantity that its market's scale
+/// cannot hold, or an order for a market that does not exist, was rejected and shows as zero.
+pub fn public_values(
+    prev: &State,
+    actions: &[Action],
+    ctx: &MatchContext,
+    next: &State,
+    output: &BatchOutput,
+) -> PublicValuesStruct {
+    let mut values = batch_values(actions, ctx, output, |_| Some(&next.config));
+    values.prevState = prev.commitment().into();
+    values.newState = next.commitment().into();
+    values.tradeChain = next.trade_chain.into();
+    values.balanceRoot = next.balance_root().into();
+    values
+}
+
+/// The public values that depend only on the batch itself, with the state fields left zero.
+/// `config` gives the market an order went to.
+fn batch_values<'a>(
+    actions: &[Action],
+    ctx: &MatchContext,
+    output: &BatchOutput,
+    config: impl Fn(&Order) -> Option<&'a MarketConfig>,
+) -> PublicValuesStruct {
+    let orders: Vec<&Order> = actions
+        .iter()
+        .filter_map(|action| match action {
+            Action::Place(order) => Some(order),
+            _ => None,
+        })
+        .collect();
+    PublicValuesStruct {
+        prevState: Default::default(),
+        actionsHash: actions_hash(actions).into(),
+        timestamp: ctx.timestamp,
+        traders: orders
+            .iter()
+            .map(|order| order.address.parse().unwrap_or_default())
+            .collect(),
+        markets: orders.iter().map(|order| order.market.clone()).collect(),
+        orderTypes: orders
+            .iter()
+            .map(|order| commitment::order_type_tag(order.order_type))
+            .collect(),
+        price: orders
+            .iter()
+            .map(|order| {
+                let scale = config(order).map(|config| config.price_decimals);
+                order
+                    .price
+                    .zip(scale)
+                    .and_then(|(price, scale)| price.to_u256(scale))
+                    .unwrap_or_default()
+            })
+            .collect(),
+        quantity: orders
+            .iter()
+            .map(|order| {
+                let scale = config(order).map(|config| config.quantity_decimals);
+                scale
+                    .and_then(|scale| order.quantity.to_u256(scale))
+                    .unwrap_or_default()
+            })
+            .collect(),
+        newState: Default::default(),
+        eventsHash: event_hash(&output.events).into(),
+        tradeChain: Default::default(),
+        balanceRoot: Default::default(),
+        clearingsHash: clearings_hash(&output.clearings).into(),
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
 pub struct State {
-    pub pending_bid_orders: Vec<Order>,
-    pub pending_ask_orders: Vec<Order>,
-    pub trades: Vec<Trade>,
+    pub config: MarketConfig,
+    pub bids: BookSide,
+    pub asks: BookSide,
+    /// Running commitment to every trade so far (see `chain_trades`). The trades themselves are
+    /// handed out in each `ActionReport` and not kept, so the state does not grow with history.
+    pub trade_chain: [u8; 32],
+    /// Every fee charged so far, in quote token units. The fees themselves are credited to
+    /// `config.fees.collector`'s balance.
+    pub collected_fees: u128,
+    /// Price of the most recent trade, which stop orders trigger on.
+    pub last_price: Option<Price>,
+    /// Stop orders waiting for their trigger, oldest first.
+    pub stop_orders: Vec<Order>,
+    /// The last sequence number handed out. Orders and trades share one counter.
+    pub sequence: u64,
+    pub balances: Balances,
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self::with_config(MarketConfig::default())
+    }
+
+    pub fn with_config(config: MarketConfig) -> Self {
+        Self {
+            config,
+            bids: BookSide::new(OrderType::Bid),
+            asks: BookSide::new(OrderType::Ask),
+            trade_chain: [0; 32],
+            collected_fees: 0,
+            last_price: None,
+            stop_orders: vec![],
+            sequence: 0,
+            balances: Balances::default(),
+        }
+    }
+
+    fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
+    }
+
+    pub fn side(&self, side: OrderType) -> &BookSide {
+        match side {
+            OrderType::Bid => &self.bids,
+            OrderType::Ask => &self.asks,
+        }
+    }
+
+    fn side_mut(&mut self, side: OrderType) -> &mut BookSide {
+        match side {
+            OrderType::Bid => &mut self.bids,
+            OrderType::Ask => &mut self.asks,
+        }
+    }
+
+    pub fn best_bid(&self) -> Option<Price> {
+        self.bids.best_price()
+    }
+
+    pub fn best_ask(&self) -> Option<Price> {
+        self.asks.best_price()
+    }
+
+    /// Best ask minus best bid, when both sides have orders.
+    pub fn spread(&self) -> Option<Price> {
+        self.best_ask()?.checked_sub(self.best_bid()?)
+    }
+
+    /// Halfway between the best bid and ask, with one more decimal place when needed.
+    pub fn mid_price(&self) -> Option<Price> {
+        Some(midpoint(self.best_ask()?, self.best_bid()?))
+    }
+
+    /// The top `levels` price levels of each side with their displayed quantity.
+    pub fn depth(&self, levels: usize) -> Depth {
+        Depth::new(&self.bids, &self.asks, levels)
+    }
+
+    /// The resting or untriggered stop order with this id.
+    pub fn order(&self, id: &str) -> Option<&Order> {
+        self.bids
+            .get(id)
+            .or_else(|| self.asks.get(id))
+            .or_else(|| self.stop_orders.iter().find(|order| order.id == id))
+    }
+
+    /// All open orders of `address`: its bids and asks by id, then its stop orders oldest first.
+    pub fn open_orders(&self, address: &str) -> Vec<&Order> {
+        self.bids
+            .orders_of(address)
+            .chain(self.asks.orders_of(address))
+            .chain(
+                self.stop_orders
+                    .iter()
+                    .filter(|order| order.address == address),
+            )
+            .collect()
+    }
+
+    /// Root of the Merkle tree over every balance. Applying a batch's `balance_updates` to the
+    /// tree of the previous state gives the same root.
+    pub fn balance_root(&self) -> [u8; 32] {
+        self.balances.root()
+    }
+
+    /// Which side of the book holds the resting order `id`, if any.
+    fn find_order(&self, id: &str) -> Option<OrderType> {
+        [OrderType::Bid, OrderType::Ask]
+            .into_iter()
+            .find(|side| self.side(*side).get(id).is_some())
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rules that apply to every order in the market.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub struct MarketConfig {
+    /// Applied when an order does not choose its own mode.
+    pub self_trade_prevention: SelfTradePrevention,
+    pub fees: FeeSchedule,
+    pub price_rule: PriceRule,
+    /// The token that order quantities are amounts of.
+    pub base: Token,
+    /// The token that prices are quoted in and fees are charged in.
+    pub quote: Token,
+    /// Decimal places of every price in the market. Prices and triggers are converted to this scale
+    /// on arrival, and an order with a finer price is rejected.
+    pub price_decimals: u8,
+    /// Decimal places of every quantity in the market, as `price_decimals` is for prices.
+    /// Should not exceed the base token's decimals.
+    pub quantity_decimals: u8,
+    /// Prices and stop triggers must be whole multiples of this. `None` allows any price.
+    pub tick_size: Option<Price>,
+    /// Quantities, displayed slices included, must be whole multiples of this. `None` allows any
+    /// quantity the base token can represent.
+    pub lot_size: Option<Quantity>,
+    /// Smallest quantity an order may be placed or amended to.
+    pub min_quantity: Quantity,
+    /// Smallest value, in quote token units, of a limit order at its own price. Market orders
+    /// have no price to value them at and are exempt.
+    pub min_notional: u128,
+    pub matching_mode: MatchingMode,
+    /// How an incoming order is shared among the orders at each price it reaches. Batch auctions
+    /// always pair orders in price-time priority.
+    pub allocation: Allocation,
+}
+
+/// How incoming orders meet the book.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum MatchingMode {
+    /// Each order matches against the book as it arrives.
+    #[default]
+    Continuous,
+    /// Orders only rest as they arrive, and `apply_actions` crosses the whole book at one clearing
+    /// price at the end of the batch (see `clear_auction`), so their order within the batch does
+    /// not matter. Takes limit orders only, without post-only, fill-or-kill or stop triggers.
+    BatchAuction,
+}
+
+impl Default for MarketConfig {
+    fn default() -> Self {
+        Self {
+            self_trade_prevention: SelfTradePrevention::default(),
+            fees: FeeSchedule::default(),
+            price_rule: PriceRule::default(),
+            base: Token::new("BASE", MAX_SCALE),
+            quote: Token::new("QUOTE", MAX_SCALE),
+            price_decimals: 8,
+            quantity_decimals: 8,
+            tick_size: None,
+            lot_size: None,
+            min_quantity: Quantity::zero(0),
+            min_notional: 0,
+            matching_mode: MatchingMode::default(),
+            allocation: Allocation::default(),
+        }
+    }
+}
+
+impl MarketConfig {
+    /// `quantity` in base token units. Exact for every quantity the book accepts, since those
+    /// have no more decimal places than the base token.
+    pub fn base_amount(&self, quantity: Quantity) -> u128 {
+        self.base
+            .amount(quantity.lots as u128, quantity.scale as u32, false)
+            .unwrap_or(u128::MAX)
+    }
+
+    /// `price` times `quantity` in quote token units, rounded down. This is what a trade pays.
+    pub fn quote_amount(&self, price: Price, quantity: Quantity) -> u128 {
+        self.cost(price, quantity, false)
+    }
+
+    // Saturates, since no balance can cover an amount that does not fit
+    fn cost(&self, price: Price, quantity: Quantity, round_up: bool) -> u128 {
+        let units = price.ticks as u128 * quantity.lots as u128;
+        let scale = price.scale as u32 + quantity.scale as u32;
+        self.quote
+            .amount(units, scale, round_up)
+            .unwrap_or(u128::MAX)
+    }
+
+    /// `price` at the market's price scale.
+    pub fn market_price(&self, price: Price) -> Result<Price, MatchError> {
+        // Normalizing first also brings a scale beyond `MAX_SCALE` back in range when it can be
+        let normalized = price.normalized();
+        if normalized.scale > self.price_decimals {
+            return Err(MatchError::PriceTooPrecise(price));
+        }
+        normalized
+            .to_scale(self.price_decimals)
+            .ok_or(MatchError::OutOfRange)
+    }
+
+    /// `quantity` at the market's quantity scale.
+    pub fn market_quantity(&self, quantity: Quantity) -> Result<Quantity, MatchError> {
+        let normalized = quantity.normalized();
+        if normalized.scale > self.quantity_decimals {
+            return Err(MatchError::TooPrecise(quantity));
+        }
+        normalized
+            .to_scale(self.quantity_decimals)
+            .ok_or(MatchError::OutOfRange)
+    }
+
+    /// `order` with every price and quantity at the market's scales, so that the book only ever
+    /// does arithmetic between values of one scale.
+    fn to_market_scale(&self, order: Order) -> Result<Order, MatchError> {
+        let price = |price: Option<Price>| price.map(|price| self.market_price(price)).transpose();
+        Ok(Order {
+            price: price(order.price)?,
+            trigger: price(order.trigger)?,
+            quantity: self.market_quantity(order.quantity)?,
+            display_quantity: order
+                .display_quantity
+                .map(|display| self.market_quantity(display))
+                .transpose()?,
+            hidden_quantity: self.market_quantity(order.hidden_quantity)?,
+            ..order
+        })
+    }
+
+    /// Checks an order's prices and sizes against the market's rules. `quantity` is the order's
+    /// total, hidden reserve included.
+    pub fn check_order(
+        &self,
+        price: Option<Price>,
+        trigger: Option<Price>,
+        quantity: Quantity,
+        display_quantity: Option<Quantity>,
+    ) -> Result<(), MatchError> {
+        for quantity in [Some(quantity), display_quantity].into_iter().flatten() {
+            if quantity.normalized().scale > self.base.decimals {
+                return Err(MatchError::TooPrecise(quantity));
+            }
+            if self
+                .lot_size
+                .is_some_and(|lot| !quantity.is_multiple_of(lot))
+            {
+                return Err(MatchError::OffLot(quantity));
+            }
+        }
+        if let Some(price) = [price, trigger].into_iter().flatten().find(|price| {
+            self.tick_size
+                .is_some_and(|tick| !price.is_multiple_of(tick))
+        }) {
+            return Err(MatchError::OffTick(price));
+        }
+        if quantity < self.min_quantity {
+            return Err(MatchError::BelowMinQuantity(quantity));
+        }
+        if let Some(price) = price {
+            let notional = self.quote_amount(price, quantity);
+            if notional < self.min_notional {
+                return Err(MatchError::BelowMinNotional {
+                    notional,
+                    minimum: self.min_notional,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// The token an order of this side pays with and locks: quote for a bid, base for an ask.
+    pub fn paying_token(&self, order_type: OrderType) -> &Token {
+        match order_type {
+            OrderType::Bid => &self.quote,
+            OrderType::Ask => &self.base,
+        }
+    }
+}
+
+/// A token the market settles in.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub struct Token {
+    /// Keys the token's balances and balance tree leaves, normally its contract address.
+    pub address: String,
+    /// Decimal places of the token's smallest unit, which balances count in.
+    pub decimals: u8,
+}
+
+impl Token {
+    pub fn new(address: &str, decimals: u8) -> Self {
+        Self {
+            address: address.to_string(),
+            decimals,
+        }
+    }
+
+    /// `units` with `scale` decimal places in this token's smallest unit, rounded down or up when
+    /// the token has fewer decimals. `None` on overflow.
+    fn amount(&self, units: u128, scale: u32, round_up: bool) -> Option<u128> {
+        let decimals = self.decimals as u32;
+        if scale <= decimals {
+            return units.checked_mul(10u128.checked_pow(decimals - scale)?);
+        }
+        let divisor = 10u128.checked_pow(scale - decimals)?;
+        Some(if round_up {
+            units.div_ceil(divisor)
+        } else {
+            units / divisor
+        })
+    }
+}
+
+/// Which price a trade executes at when the taker's limit is better than the maker's price.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum PriceRule {
+    /// The resting order's price, so the taker keeps any price improvement.
+    #[default]
+    Maker,
+    /// The taker's limit price. Market orders still trade at the resting price.
+    Taker,
+    /// Halfway between the two, shared between maker and taker.
+    Midpoint,
+}
+
+impl PriceRule {
+    fn trade_price(self, taker_limit: Option<Price>, maker_price: Price) -> Price {
+        match (self, taker_limit) {
+            (PriceRule::Maker, _) | (_, None) => maker_price,
+            (PriceRule::Taker, Some(limit)) => limit,
+            (PriceRule::Midpoint, Some(limit)) => midpoint(limit, maker_price),
+        }
+    }
+}
+
+/// The price halfway between `taker` and `maker`, with one more decimal place when needed. If
+/// that place is not available it rounds towards the maker's price.
+fn midpoint(taker: Price, maker: Price) -> Price {
+    let scale = taker.scale.max(maker.scale);
+    let (a, b) = match (taker.to_scale(scale), maker.to_scale(scale)) {
+        (Some(a), Some(b)) => (a.ticks as u128, b.ticks as u128),
+        _ => return maker,
+    };
+    let sum = a + b;
+    if sum % 2 == 0 {
+        return Price::new((sum / 2) as u64, scale);
+    }
+    if scale < MAX_SCALE {
+        if let Ok(ticks) = u64::try_from(sum * 5) {
+            return Price::new(ticks, scale + 1);
+        }
+    }
+    let ticks = if b < a { sum / 2 } else { sum / 2 + 1 };
+    Price::new(ticks as u64, scale)
+}
+
+/// Fee rates in basis points of a trade's value in quote token units.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub struct FeeRates {
+    pub maker_bps: u32,
+    pub taker_bps: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash, Default)]
+pub struct FeeSchedule {
+    pub default: FeeRates,
+    /// Per-address rates that replace `default` for that trader, keyed by lowercase address.
+    pub tiers: BTreeMap<String, FeeRates>,
+    /// The account that fees accrue to. Must be a valid address once any rate is above zero.
+    pub collector: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+impl FeeSchedule {
+    /// Whether any trader pays a fee at all.
+    pub fn charges_fees(&self) -> bool {
+        self.default != FeeRates::default()
+            || self
+                .tiers
+                .values()
+                .any(|rates| *rates != FeeRates::default())
+    }
+
+    pub fn rates_for(&self, address: &str) -> FeeRates {
+        self.tiers.get(address).copied().unwrap_or(self.default)
+    }
+}
+
+/// `bps` basis points of `amount`, rounded down. Split so the product cannot overflow.
+fn fee(amount: u128, bps: u32) -> u128 {
+    let bps = bps as u128;
+    amount / 10_000 * bps + amount % 10_000 * bps / 10_000
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
 pub struct Trade {
-    pub id: String,
+    /// The trade's sequence number, unique within the market. Each market of an `Exchange` counts
+    /// its own, so across markets a trade is identified by `(ask_order.market, id)`.
+    pub id: u64,
     pub ask_order: Order,
     pub bid_order: Order,
-    pub price: f64,
-    pub quantity: u64,
+    pub price: Price,
+    pub quantity: Quantity,
+    /// The side of the incoming order that took liquidity.
+    pub taker_side: OrderType,
+    /// Fees in quote token units, taken from what the seller receives or added to what the
+    /// buyer pays.
+    pub maker_fee: u128,
+    pub taker_fee: u128,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+impl Trade {
+    /// Price times quantity as an integer with `price.scale + quantity.scale` decimal places.
+    /// `MarketConfig::quote_amount` gives the same value in quote token units.
+    pub fn notional(&self) -> u128 {
+        self.price.ticks as u128 * self.quantity.lots as u128
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
 pub struct Order {
     pub id: String,
     pub address: String,
+    /// The market an `Exchange` routes the order to. A lone `State` is a single market and
+    /// ignores it.
+    pub market: String,
     pub order_type: OrderType,
-    pub price: f64,
-    pub quantity: u64,
+    /// Limit price, or `None` for a market order that takes whatever liquidity is available.
+    /// Resting orders always have a price.
+    pub price: Option<Price>,
+    pub quantity: Quantity,
+    /// Caps how far, in basis points, a market order may trade past the best opposite price
+    /// at the time it arrives. Ignored for limit orders.
+    pub max_slippage_bps: Option<u32>,
+    pub time_in_force: TimeInForce,
+    /// Makes the order maker-only: it never takes liquidity on arrival.
+    pub post_only: Option<PostOnly>,
+    /// Overrides the market's self-trade prevention mode for this order when it is the taker.
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    /// Makes this a stop order: it waits off the book until the last trade price reaches the
+    /// trigger (at or above it for a bid, at or below it for an ask), then enters as a market
+    /// order, or as a limit order if `price` is set.
+    pub trigger: Option<Price>,
+    /// Makes this an iceberg order that shows at most this much at a time. While it rests,
+    /// `quantity` is the displayed slice and `hidden_quantity` the reserve behind it.
+    pub display_quantity: Option<Quantity>,
+    /// Set by the engine when it splits an iceberg. Any value given on submission is ignored.
+    pub hidden_quantity: Quantity,
+    /// Set by the engine each time the order enters the book, and decides time priority within a
+    /// price. Any value given on submission is overwritten.
+    pub sequence: u64,
+    /// Funds the order holds, in `MarketConfig::paying_token` units. Set by the engine when the
+    /// order enters the book and released as it fills or leaves.
+    pub locked: u128,
+}
+
+impl Order {
+    pub fn limit(
+        id: &str,
+        address: &str,
+        order_type: OrderType,
+        price: Price,
+        quantity: Quantity,
+    ) -> Self {
+        Self {
+            id: id.to_string(),
+            address: address.to_string(),
+            market: String::new(),
+            order_type,
+            price: Some(price),
+            quantity,
+            max_slippage_bps: None,
+            time_in_force: TimeInForce::GoodTilCancelled,
+            post_only: None,
+            self_trade_prevention: None,
+            trigger: None,
+            display_quantity: None,
+            hidden_quantity: Quantity::zero(quantity.scale),
+            sequence: 0,
+            locked: 0,
+        }
+    }
+
+    pub fn market(id: &str, address: &str, order_type: OrderType, quantity: Quantity) -> Self {
+        Self {
+            price: None,
+            ..Self::limit(id, address, order_type, Price::zero(0), quantity)
+        }
+    }
+
+    pub fn is_market(&self) -> bool {
+        self.price.is_none()
+    }
+
+    pub fn is_expired(&self, ctx: &MatchContext) -> bool {
+        matches!(
+            self.time_in_force,
+            TimeInForce::GoodTilTime(expires_at) if expires_at <= ctx.timestamp
+        )
+    }
+
+    /// Displayed plus hidden quantity.
+    pub fn total_quantity(&self) -> Quantity {
+        self.quantity
+            .checked_add(self.hidden_quantity)
+            .expect("the engine only ever splits an order's own quantity")
+    }
+
+    /// Takes `amount` off the order, from the displayed slice first and then from the reserve.
+    pub(crate) fn reduce(&mut self, amount: Quantity) {
+        let from_display = amount.min(self.quantity);
+        self.quantity -= from_display;
+        self.hidden_quantity -= amount - from_display;
+    }
+
+    /// Splits an iceberg's total into a displayed slice and a hidden reserve.
+    fn split_display(&mut self) {
+        if let Some(display) = self.display_quantity {
+            let total = self.total_quantity();
+            self.quantity = display.min(total);
+            self.hidden_quantity = total - self.quantity;
+        }
+    }
+
+    fn is_triggered(&self, last_price: Option<Price>) -> bool {
+        match (self.trigger, last_price) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(trigger), Some(last)) => match self.order_type {
+                OrderType::Bid => last >= trigger,
+                OrderType::Ask => last <= trigger,
+            },
+        }
+    }
+
+    /// Whether an unfilled remainder may rest on the book.
+    fn rests(&self) -> bool {
+        !self.is_market()
+            && matches!(
+                self.time_in_force,
+                TimeInForce::GoodTilCancelled | TimeInForce::GoodTilTime(_)
+            )
+    }
+
+    pub(crate) fn resting_price(&self) -> Price {
+        self.price
+            .expect("resting orders always have a limit price")
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Hash)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
 pub enum OrderType {
     Bid,
     Ask,
 }
 
-impl Hash for Order{
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
-        self.address.hash(state);
-        self.order_type.hash(state);
-        // Hash f64 as its bit representation
-        let normalized = if self.price == -0.0 { 0.0 } else { self.price };
-        normalized.to_bits().hash(state);
+impl OrderType {
+    pub fn opposite(self) -> Self {
+        match self {
+            OrderType::Bid => OrderType::Ask,
+            OrderType::Ask => OrderType::Bid,
+        }
+    }
+}
+
+/// How long an order stays eligible to trade.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum TimeInForce {
+    GoodTilCancelled,
+    /// Trades what it can on arrival; the remainder is dropped.
+    ImmediateOrCancel,
+    /// Trades its full quantity on arrival or not at all.
+    FillOrKill,
+    /// Rests like good-til-cancelled until the `MatchContext` timestamp reaches this value.
+    GoodTilTime(u64),
+}
+
+/// What to do with a post-only order that would cross the opposite side on arrival.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum PostOnly {
+    Reject,
+    /// Moves the price to one tick (at the order's own scale) short of the opposite best price.
+    Reprice,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum PostOnlyOutcome {
+    /// Did not cross; placed at its own price.
+    Accepted,
+    Repriced(Price),
+    Rejected,
+}
+
+/// What happens when an incoming order meets a resting order from the same address. The incoming
+/// order's mode decides.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum SelfTradePrevention {
+    /// Cancels the rest of the incoming order.
+    #[default]
+    CancelNewest,
+    /// Cancels the resting order and keeps matching.
+    CancelOldest,
+    CancelBoth,
+    /// Reduces both orders by the smaller quantity, which cancels the smaller one outright.
+    DecrementAndCancel,
+}
+
+/// A match that self-trade prevention stopped, with the quantity it took off each order.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub struct SelfTradePrevented {
+    pub address: String,
+    pub mode: SelfTradePrevention,
+    pub taker_order_id: String,
+    pub maker_order_id: String,
+    pub taker_cancelled: Quantity,
+    pub maker_cancelled: Quantity,
+}
+
+/// One step in the life of an order. Quantities count an iceberg's hidden reserve.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub enum OrderEvent {
+    /// Passed validation and went into matching, or into the trigger book for a stop order. Also
+    /// emitted when an amend replaces a resting order.
+    Accepted {
+        order_id: String,
+        trader: String,
+        quantity: Quantity,
+    },
+    Rested {
+        order_id: String,
+        trader: String,
+        price: Price,
+        quantity: Quantity,
+    },
+    PartiallyFilled {
+        order_id: String,
+        trader: String,
+        filled: Quantity,
+        remaining: Quantity,
+    },
+    Filled {
+        order_id: String,
+        trader: String,
+        filled: Quantity,
+    },
+    /// Quantity taken off by a cancel or amend, by self-trade prevention, or because an immediate
+    /// order could not rest. The order is gone once `remaining` is zero. Cancels by self-trade
+    /// prevention follow the `SelfTradePrevented` event that explains them.
+    Cancelled {
+        order_id: String,
+        trader: String,
+        cancelled: Quantity,
+        remaining: Quantity,
+    },
+    Rejected {
+        order_id: String,
+        trader: String,
+        quantity: Quantity,
+        reason: RejectReason,
+    },
+    Expired {
+        order_id: String,
+        trader: String,
+        quantity: Quantity,
+    },
+    /// Two orders from one address met and self-trade prevention cancelled quantity instead.
+    SelfTradePrevented(SelfTradePrevented),
+}
+
+impl OrderEvent {
+    /// A fill of `filled` from `order`, which has already been reduced by it.
+    fn fill(order: &Order, filled: Quantity) -> Self {
+        let remaining = order.total_quantity();
+        let (order_id, trader) = (order.id.clone(), order.address.clone());
+        if remaining.is_zero() {
+            OrderEvent::Filled {
+                order_id,
+                trader,
+                filled,
+            }
+        } else {
+            OrderEvent::PartiallyFilled {
+                order_id,
+                trader,
+                filled,
+                remaining,
+            }
+        }
+    }
+
+    /// `cancelled` taken off `order`, which has already been reduced by it.
+    fn cancelled(order: &Order, cancelled: Quantity) -> Self {
+        OrderEvent::Cancelled {
+            order_id: order.id.clone(),
+            trader: order.address.clone(),
+            cancelled,
+            remaining: order.total_quantity(),
+        }
+    }
 
-        self.quantity.hash(state);
+    fn rejected(order: &Order, reason: RejectReason) -> Self {
+        OrderEvent::Rejected {
+            order_id: order.id.clone(),
+            trader: order.address.clone(),
+            quantity: order.quantity,
+            reason,
+        }
     }
 }
 
-impl Hash for Trade{
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
-        self.ask_order.hash(state);
-        self.bid_order.hash(state);
-        // Hash f64 as its bit representation
-        let normalized = if self.price == -0.0 { 0.0 } else { self.price };
-        normalized.to_bits().hash(state);
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub enum RejectReason {
+    /// Failed validation. The action returned this error, unless a stop order failed the balance
+    /// check when it triggered.
+    Invalid(MatchError),
+    /// A post-only order that would have taken liquidity.
+    PostOnly,
+    /// A fill-or-kill order that the book could not fill in full, or a fill-or-kill market bid that
+    /// its trader could not pay for in full.
+    FillOrKill,
+}
+
+/// What happened while applying an action, beyond what can be read off the resulting `State`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash, Default)]
+pub struct ActionReport {
+    /// Set when the action placed a post-only order.
+    pub post_only: Option<PostOnlyOutcome>,
+    /// Matches prevented because both orders came from the same address, in the order they met.
+    pub self_trades: Vec<SelfTradePrevented>,
+    /// Ids of stop orders that triggered and entered the book, in activation order.
+    pub triggered_stops: Vec<String>,
+    /// Everything that happened to orders, in the order it happened.
+    pub events: Vec<OrderEvent>,
+    /// Trades executed, in order.
+    pub trades: Vec<Trade>,
+}
+
+/// What a batch of actions produced.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash, Default)]
+pub struct BatchOutput {
+    pub events: Vec<OrderEvent>,
+    pub trades: Vec<Trade>,
+    /// The final value of every balance the batch changed, ordered by trader and then token.
+    pub balance_updates: Vec<BalanceUpdate>,
+    /// Auctions run at the end of the batch that traded, one per market in batch auction mode.
+    pub clearings: Vec<Clearing>,
+}
+
+/// Inputs to matching that come from outside the book. They are part of the proven input so that
+/// time-dependent behaviour such as expiry is deterministic.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub struct MatchContext {
+    /// Unix time in seconds.
+    pub timestamp: u64,
+}
+
+/// An instruction from a trader to the book.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub enum Action {
+    Place(Order),
+    Cancel {
+        id: String,
+    },
+    /// Cancels every open order of `address`, stop orders included.
+    CancelAll {
+        address: String,
+    },
+    /// Changes a resting order. Reducing the quantity keeps the order's time priority; a new price
+    /// or a larger quantity sends it to the back of the queue as if it had just arrived.
+    Amend {
+        id: String,
+        price: Option<Price>,
+        quantity: Option<Quantity>,
+    },
+    /// Credits `amount` of `token`, in its smallest unit, to `address`. The token must be the
+    /// market's base or quote.
+    Deposit {
+        address: String,
+        token: String,
+        amount: u128,
+    },
+    /// Debits `amount` of `token` from the available balance of `address`.
+    Withdraw {
+        address: String,
+        token: String,
+        amount: u128,
+    },
+}
+
+/// Why an action was rejected. A rejected action leaves the `State` untouched.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub enum MatchError {
+    EmptyOrderId,
+    DuplicateOrderId(String),
+    UnknownOrderId(String),
+    /// Addresses, the fee collector's included, must be `0x` followed by 40 lowercase hex digits.
+    InvalidAddress(String),
+    ZeroQuantity,
+    ZeroPrice,
+    /// A good-til-time order arrived at or after its own expiry.
+    AlreadyExpired,
+    /// A quantity has more decimal places than the market or its base token allows.
+    TooPrecise(Quantity),
+    /// A price or trigger has more decimal places than the market allows.
+    PriceTooPrecise(Price),
+    /// A price or quantity too large to hold at the market's scale.
+    OutOfRange,
+    /// A price or trigger that is not a multiple of the market's tick size.
+    OffTick(Price),
+    /// A quantity that is not a multiple of the market's lot size.
+    OffLot(Quantity),
+    BelowMinQuantity(Quantity),
+    /// The order's value in quote token units is below the market's minimum.
+    BelowMinNotional {
+        notional: u128,
+        minimum: u128,
+    },
+    /// Neither the market's base nor its quote token.
+    UnknownToken(String),
+    InsufficientBalance {
+        token: String,
+        required: u128,
+        available: u128,
+    },
+    /// A deposit would take a balance past `u128::MAX`.
+    BalanceOverflow,
+    /// No market of the `Exchange` has this id.
+    UnknownMarket(String),
+    /// A market, stop, post-only or fill-or-kill order sent to a market in batch auction mode.
+    UnsupportedInAuction,
+}
 
-        self.quantity.hash(state);
+impl fmt::Display for MatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchError::EmptyOrderId => write!(f, "order id is empty"),
+            MatchError::DuplicateOrderId(id) => write!(f, "order id {} is already on the book", id),
+            MatchError::UnknownOrderId(id) => write!(f, "no resting order with id {}", id),
+            MatchError::InvalidAddress(address) => write!(f, "invalid address {:?}", address),
+            MatchError::ZeroQuantity => write!(f, "quantity must be positive"),
+            MatchError::ZeroPrice => write!(f, "price must be positive"),
+            MatchError::AlreadyExpired => write!(f, "order expired before it arrived"),
+            MatchError::TooPrecise(quantity) => {
+                write!(f, "quantity {} is finer than the market allows", quantity)
+            }
+            MatchError::PriceTooPrecise(price) => {
+                write!(f, "price {} is finer than the market allows", price)
+            }
+            MatchError::OutOfRange => write!(f, "value is too large for the market's scale"),
+            MatchError::OffTick(price) => {
+                write!(f, "price {} is not a multiple of the tick size", price)
+            }
+            MatchError::OffLot(quantity) => {
+                write!(f, "quantity {} is not a multiple of the lot size", quantity)
+            }
+            MatchError::BelowMinQuantity(quantity) => {
+                write!(f, "quantity {} is below the minimum", quantity)
+            }
+            MatchError::BelowMinNotional { notional, minimum } => {
+                write!(
+                    f,
+                    "order value {} is below the minimum of {}",
+                    notional, minimum
+                )
+            }
+            MatchError::UnknownToken(token) => write!(f, "token {} is not traded here", token),
+            MatchError::InsufficientBalance {
+                token,
+                required,
+                available,
+            } => {
+                write!(
+                    f,
+                    "needs {} of {} but only {} is available",
+                    required, token, available
+                )
+            }
+            MatchError::BalanceOverflow => write!(f, "balance would overflow"),
+            MatchError::UnknownMarket(market) => write!(f, "no market {:?}", market),
+            MatchError::UnsupportedInAuction => {
+                write!(f, "order type is not supported in a batch auction")
+            }
+        }
     }
 }
 
-pub fn match_order(mut curr_state: State, mut new_order: Order) -> State {
-    match new_order.order_type {
-        OrderType::Ask => {
-            let mut pending_bid_orders = std::mem::take(&mut curr_state.pending_bid_orders);
-            process_order(
-                &mut curr_state,
-                &mut new_order,
-                &mut pending_bid_orders,
-                OrderType::Ask,
-            );
-            curr_state.pending_bid_orders = pending_bid_orders;
-        }
-        OrderType::Bid => {
-            let mut pending_ask_orders = std::mem::take(&mut curr_state.pending_ask_orders);
-            process_order(
-                &mut curr_state,
-                &mut new_order,
-                &mut pending_ask_orders,
-                OrderType::Bid,
-            );
-            curr_state.pending_ask_orders = pending_ask_orders;
+impl std::error::Error for MatchError {}
+
+/// Applies one action to the book. On error nothing is changed, so a batch can skip the action
+/// and carry on.
+pub fn apply_action(
+    state: &mut State,
+    action: Action,
+    ctx: &MatchContext,
+) -> Result<ActionReport, MatchError> {
+    match action {
+        Action::Place(order) => {
+            let allocation = state.config.allocation;
+            match_order(state, order, ctx, &allocation)
+        }
+        Action::Cancel { id } => {
+            let mut report = ActionReport::default();
+            if let Some(index) = state.stop_orders.iter().position(|order| order.id == id) {
+                let mut order = state.stop_orders.remove(index);
+                expire_orders(state, ctx, &mut report.events);
+                report.events.push(cancel_event(&mut order));
+                return Ok(report);
+            }
+            let side = state
+                .find_order(&id)
+                .ok_or(MatchError::UnknownOrderId(id.clone()))?;
+            expire_orders(state, ctx, &mut report.events);
+            // Expiry may have removed the order already
+            if let Some(mut order) = state.side_mut(side).remove(&id) {
+                release_all(state, &mut order);
+                report.events.push(cancel_event(&mut order));
+            }
+            Ok(report)
+        }
+        Action::CancelAll { address } => cancel_all(state, &address, ctx),
+        Action::Amend {
+            id,
+            price,
+            quantity,
+        } => amend_order(state, &id, price, quantity, ctx),
+        Action::Deposit {
+            address,
+            token,
+            amount,
+        } => {
+            validate_transfer(state, &address, &token, amount)?;
+            state.balances.deposit(&address, &token, amount)?;
+            let mut report = ActionReport::default();
+            expire_orders(state, ctx, &mut report.events);
+            Ok(report)
+        }
+        Action::Withdraw {
+            address,
+            token,
+            amount,
+        } => {
+            validate_transfer(state, &address, &token, amount)?;
+            state.balances.withdraw(&address, &token, amount)?;
+            let mut report = ActionReport::default();
+            expire_orders(state, ctx, &mut report.events);
+            Ok(report)
         }
     }
+}
 
-    curr_state
+fn validate_transfer(
+    state: &State,
+    address: &str,
+    token: &str,
+    amount: u128,
+) -> Result<(), MatchError> {
+    if !is_valid_address(address) {
+        return Err(MatchError::InvalidAddress(address.to_string()));
+    }
+    if token != state.config.base.address && token != state.config.quote.address {
+        return Err(MatchError::UnknownToken(token.to_string()));
+    }
+    if amount == 0 {
+        return Err(MatchError::ZeroQuantity);
+    }
+    Ok(())
 }
 
-fn process_order(
+fn cancel_all(
     state: &mut State,
-    new_order: &mut Order,
-    matching_orders: &mut Vec<Order>,
-    order_type: OrderType,
-) {
-    if let Some(index) = find_order(matching_orders, new_order.price) {
-        let matched_order = &mut matching_orders[index];
-        let trade_quantity = matched_order.quantity.min(new_order.quantity);
-
-        let trade = Trade {
-            id: format!("{}-{}", matched_order.id, new_order.id),
-            ask_order: if order_type == OrderType::Ask {
-                new_order.clone()
-            } else {
-                matched_order.clone()
-            },
-            bid_order: if order_type == OrderType::Bid {
-                new_order.clone()
-            } else {
-                matched_order.clone()
-            },
-            price: new_order.price,
-            quantity: trade_quantity,
-        };
+    address: &str,
+    ctx: &MatchContext,
+) -> Result<ActionReport, MatchError> {
+    if !is_valid_address(address) {
+        return Err(MatchError::InvalidAddress(address.to_string()));
+    }
+    let mut report = ActionReport::default();
+    expire_orders(state, ctx, &mut report.events);
+    for side in [OrderType::Bid, OrderType::Ask] {
+        let ids: Vec<String> = state
+            .side(side)
+            .orders_of(address)
+            .map(|order| order.id.clone())
+            .collect();
+        for id in ids {
+            let mut order = state
+                .side_mut(side)
+                .remove(&id)
+                .expect("indexed order is on the book");
+            release_all(state, &mut order);
+            report.events.push(cancel_event(&mut order));
+        }
+    }
+    let (cancelled, kept) = std::mem::take(&mut state.stop_orders)
+        .into_iter()
+        .partition::<Vec<_>, _>(|order| order.address == address);
+    state.stop_orders = kept;
+    for mut order in cancelled {
+        report.events.push(cancel_event(&mut order));
+    }
+    Ok(report)
+}
 
-        state.trades.push(trade);
+/// Applies `actions` in order and returns the events and trades of the whole batch. Rejected
+/// actions are skipped; a rejected order still shows up as `OrderEvent::Rejected`, while a rejected
+/// cancel or amend names no valid order and leaves no event.
+pub fn apply_actions(state: &mut State, actions: &[Action], ctx: &MatchContext) -> BatchOutput {
+    let mut output = BatchOutput::default();
+    // Changes made before the batch are already part of the state it starts from
+    state.balances.take_updates();
+    for action in actions {
+        match apply_action(state, action.clone(), ctx) {
+            Ok(report) => {
+                output.events.extend(report.events);
+                output.trades.extend(report.trades);
+            }
+            Err(err) => {
+                if let Action::Place(order) = action {
+                    output
+                        .events
+                        .push(OrderEvent::rejected(order, RejectReason::Invalid(err)));
+                }
+            }
+        }
+    }
+    if state.config.matching_mode == MatchingMode::BatchAuction {
+        let (clearing, report) = clear_auction(state, ctx);
+        output.events.extend(report.events);
+        output.trades.extend(report.trades);
+        output.clearings.extend(clearing);
+    }
+    output.balance_updates = state.balances.take_updates();
+    output
+}
 
-        if matched_order.quantity > trade_quantity {
-            matched_order.quantity -= trade_quantity;
-        } else {
-            matching_orders.remove(index);
+/// Empties an order that is being cancelled outright.
+fn cancel_event(order: &mut Order) -> OrderEvent {
+    let cancelled = order.total_quantity();
+    order.reduce(cancelled);
+    OrderEvent::cancelled(order, cancelled)
+}
+
+/// Drops good-til-time orders whose expiry has been reached and releases their funds.
+fn expire_orders(state: &mut State, ctx: &MatchContext, events: &mut Vec<OrderEvent>) {
+    let State {
+        config,
+        bids,
+        asks,
+        stop_orders,
+        balances,
+        ..
+    } = state;
+    let mut keep = |order: &Order| {
+        let expired = order.is_expired(ctx);
+        if expired {
+            let token = &config.paying_token(order.order_type).address;
+            balances.unlock(&order.address, token, order.locked);
+            events.push(OrderEvent::Expired {
+                order_id: order.id.clone(),
+                trader: order.address.clone(),
+                quantity: order.total_quantity(),
+            });
         }
+        !expired
+    };
+    bids.retain(&mut keep);
+    asks.retain(&mut keep);
+    stop_orders.retain(|order| keep(order));
+}
 
-        if new_order.quantity > trade_quantity {
-            new_order.quantity -= trade_quantity;
-            if order_type == OrderType::Ask {
-                state.pending_ask_orders.push(new_order.clone());
-                state.pending_ask_orders
-                    .sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
-            } else {
-                state.pending_bid_orders.push(new_order.clone());
-                state.pending_bid_orders
-                    .sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap());
-            }
+/// What `order` has to lock to enter the book. An ask locks its base quantity. A limit bid locks
+/// its limit price times its quantity, rounded up, plus the higher of the trader's two fee rates
+/// on that. A market bid has no price to go by, so it locks all of the trader's available quote
+/// while it matches.
+fn required_lock(state: &State, order: &Order) -> u128 {
+    let config = &state.config;
+    match (order.order_type, order.price) {
+        (OrderType::Ask, _) => config.base_amount(order.total_quantity()),
+        (OrderType::Bid, None) => {
+            state
+                .balances
+                .get(&order.address, &config.quote.address)
+                .available
         }
-    } else {
-        if order_type == OrderType::Ask {
-            state.pending_ask_orders.push(new_order.clone());
-            state.pending_ask_orders
-                .sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
-        } else {
-            state.pending_bid_orders.push(new_order.clone());
-            state.pending_bid_orders
-                .sort_by(|a, b| b.price.partial_cmp(&a.price).unwrap());
+        (OrderType::Bid, Some(price)) => {
+            let cost = config.cost(price, order.total_quantity(), true);
+            let rates = config.fees.rates_for(&order.address);
+            cost.saturating_add(fee(cost, rates.maker_bps.max(rates.taker_bps)))
         }
     }
 }
 
+/// Checks that the trader can lock what `order` requires, counting `already_locked` funds that
+/// the order would take over.
+fn check_funds(state: &State, order: &Order, already_locked: u128) -> Result<(), MatchError> {
+    let token = &state.config.paying_token(order.order_type).address;
+    let required = required_lock(state, order);
+    let available = state
+        .balances
+        .get(&order.address, token)
+        .available
+        .saturating_add(already_locked);
+    if available < required {
+        return Err(MatchError::InsufficientBalance {
+            token: token.clone(),
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+fn release(state: &mut State, order: &mut Order, amount: u128) {
+    let token = &state.config.paying_token(order.order_type).address;
+    state.balances.unlock(&order.address, token, amount);
+    order.locked -= amount;
+}
 
-fn find_order(orders: &Vec<Order>, price: f64) -> Option<usize> {
-    for (i, order) in orders.iter().enumerate() {
-        if order.price == price {
-            return Some(i);
-        }
-        if order.price > price {
-            return None;
+fn release_all(state: &mut State, order: &mut Order) {
+    release(state, order, order.locked);
+}
+
+/// Releases whatever `order` holds beyond what its remaining quantity requires.
+fn release_excess(state: &mut State, order: &mut Order) {
+    let required = required_lock(state, order);
+    release(state, order, order.locked.saturating_sub(required));
+}
+
+fn amend_order(
+    state: &mut State,
+    id: &str,
+    price: Option<Price>,
+    quantity: Option<Quantity>,
+    ctx: &MatchContext,
+) -> Result<ActionReport, MatchError> {
+    let side = state
+        .find_order(id)
+        .ok_or(MatchError::UnknownOrderId(id.to_string()))?;
+    let resting = state.side(side).get(id).expect("order was just found");
+    let new_price = price
+        .map(|price| state.config.market_price(price))
+        .transpose()?;
+    let new_price = new_price.unwrap_or(resting.resting_price());
+    // For an iceberg the amended quantity is its total, displayed plus hidden
+    let new_quantity = quantity
+        .map(|quantity| state.config.market_quantity(quantity))
+        .transpose()?;
+    let new_quantity = new_quantity.unwrap_or(resting.total_quantity());
+    if new_price.is_zero() {
+        return Err(MatchError::ZeroPrice);
+    }
+    if new_quantity.is_zero() {
+        return Err(MatchError::ZeroQuantity);
+    }
+    state.config.check_order(
+        Some(new_price),
+        None,
+        new_quantity,
+        resting.display_quantity,
+    )?;
+    let keeps_priority =
+        new_price == resting.resting_price() && new_quantity <= resting.total_quantity();
+    let amended = Order {
+        price: Some(new_price),
+        quantity: new_quantity,
+        hidden_quantity: Quantity::zero(new_quantity.scale),
+        ..resting.clone()
+    };
+    // A replace gives up the old lock and takes a new one
+    if !keeps_priority {
+        check_funds(state, &amended, resting.locked)?;
+    }
+    let required = required_lock(state, &amended);
+
+    let mut report = ActionReport::default();
+    expire_orders(state, ctx, &mut report.events);
+    // Expiry may have removed the order
+    let Some(resting) = state.side_mut(side).get_mut(id) else {
+        return Ok(report);
+    };
+    if keeps_priority {
+        let reduction = resting.total_quantity() - new_quantity;
+        // Shrink the reserve first so the displayed slice stays as long as possible
+        let from_hidden = reduction.min(resting.hidden_quantity);
+        resting.hidden_quantity -= from_hidden;
+        resting.quantity -= reduction - from_hidden;
+        if !reduction.is_zero() {
+            report
+                .events
+                .push(OrderEvent::cancelled(resting, reduction));
         }
+        let excess = resting.locked.saturating_sub(required);
+        resting.locked -= excess;
+        let trader = resting.address.clone();
+        state
+            .balances
+            .unlock(&trader, &state.config.paying_token(side).address, excess);
+        return Ok(report);
+    }
+
+    // Anything else is a replace: the order leaves the book and is matched again, since a new
+    // price may now cross the opposite side.
+    let mut order = state
+        .side_mut(side)
+        .remove(id)
+        .expect("order was just found");
+    release_all(state, &mut order);
+    order.price = Some(new_price);
+    order.quantity = new_quantity;
+    order.hidden_quantity = Quantity::zero(new_quantity.scale);
+    let allocation = state.config.allocation;
+    place_order(state, order, &mut report, &allocation);
+    Ok(report)
+}
+
+/// Checks everything about `order` that does not depend on the opposite side of the book, and
+/// returns it at the market's scales.
+fn validate_order(state: &State, order: Order, ctx: &MatchContext) -> Result<Order, MatchError> {
+    if order.id.is_empty() {
+        return Err(MatchError::EmptyOrderId);
+    }
+    if !is_valid_address(&order.address) {
+        return Err(MatchError::InvalidAddress(order.address.clone()));
+    }
+    // Fees would otherwise accrue to an account that no one can withdraw from
+    let fees = &state.config.fees;
+    if fees.charges_fees() && !is_valid_address(&fees.collector) {
+        return Err(MatchError::InvalidAddress(fees.collector.clone()));
+    }
+    if order.quantity.is_zero()
+        || order
+            .display_quantity
+            .is_some_and(|display| display.is_zero())
+    {
+        return Err(MatchError::ZeroQuantity);
+    }
+    if [order.price, order.trigger]
+        .into_iter()
+        .flatten()
+        .any(|price| price.is_zero())
+    {
+        return Err(MatchError::ZeroPrice);
+    }
+    // Only the engine splits off a reserve, so a caller cannot hide quantity without a display
+    let order = Order {
+        hidden_quantity: Quantity::zero(0),
+        ..order
+    };
+    let order = state.config.to_market_scale(order)?;
+    let total = order.total_quantity();
+    state
+        .config
+        .check_order(order.price, order.trigger, total, order.display_quantity)?;
+    if state.config.matching_mode == MatchingMode::BatchAuction
+        && (order.is_market()
+            || order.trigger.is_some()
+            || order.post_only.is_some()
+            || order.time_in_force == TimeInForce::FillOrKill)
+    {
+        return Err(MatchError::UnsupportedInAuction);
+    }
+    if order.is_expired(ctx) {
+        return Err(MatchError::AlreadyExpired);
+    }
+    let is_stop = |stop: &Order| stop.id == order.id;
+    if state.find_order(&order.id).is_some() || state.stop_orders.iter().any(is_stop) {
+        return Err(MatchError::DuplicateOrderId(order.id.clone()));
     }
-    None
-}
\ No newline at end of file
+    // A stop order is only checked once it triggers
+    if order.is_triggered(state.last_price) {
+        check_funds(state, &order, 0)?;
+    }
+    Ok(order)
+}
+
+/// Only the lowercase form is accepted, so that balances, fee tiers and self-trade prevention,
+/// which all compare addresses as strings, see one trader as one address.
+fn is_valid_address(address: &str) -> bool {
+    address.strip_prefix("0x").is_some_and(|hex| {
+        hex.len() == 40 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    })
+}
+
+/// Validates `new_order` and matches it against the book, sharing it out within each price level
+/// as `policy` decides. `apply_action` passes the market's configured `Allocation`.
+pub fn match_order<P: MatchingPolicy>(
+    state: &mut State,
+    new_order: Order,
+    ctx: &MatchContext,
+    policy: &P,
+) -> Result<ActionReport, MatchError> {
+    let new_order = validate_order(state, new_order, ctx)?;
+    let mut report = ActionReport::default();
+    expire_orders(state, ctx, &mut report.events);
+    place_order(state, new_order, &mut report, policy);
+    Ok(report)
+}
+
+/// Parks a stop order that has not triggered yet, or matches the order, then activates any stop
+/// orders that the resulting trades triggered.
+fn place_order<P: MatchingPolicy>(
+    state: &mut State,
+    mut new_order: Order,
+    report: &mut ActionReport,
+    policy: &P,
+) {
+    new_order.sequence = state.next_sequence();
+    new_order.locked = 0;
+    report.events.push(OrderEvent::Accepted {
+        order_id: new_order.id.clone(),
+        trader: new_order.address.clone(),
+        quantity: new_order.quantity,
+    });
+    if new_order.is_triggered(state.last_price) {
+        match_against_book(
+            state,
+            Order {
+                trigger: None,
+                ..new_order
+            },
+            report,
+            policy,
+        );
+    } else {
+        state.stop_orders.push(new_order);
+    }
+    activate_stops(state, report, policy);
+}
+
+/// Activates triggered stop orders one at a time, oldest first. Each activation can move the last
+/// price and trigger further stops, so the trigger book is re-checked after every one.
+fn activate_stops<P: MatchingPolicy>(state: &mut State, report: &mut ActionReport, policy: &P) {
+    while let Some(index) = state
+        .stop_orders
+        .iter()
+        .position(|order| order.is_triggered(state.last_price))
+    {
+        let mut order = state.stop_orders.remove(index);
+        order.trigger = None;
+        // The stop only now reaches the book, so it queues behind everything already there
+        order.sequence = state.next_sequence();
+        report.triggered_stops.push(order.id.clone());
+        match_against_book(state, order, report, policy);
+    }
+}
+
+/// Locks the funds `new_order` requires and matches it. An order that cannot pay, which can only be
+/// a stop order that triggered after its trader's funds went elsewhere, is rejected instead.
+fn match_against_book<P: MatchingPolicy>(
+    state: &mut State,
+    mut new_order: Order,
+    report: &mut ActionReport,
+    policy: &P,
+) {
+    if let Err(err) = check_funds(state, &new_order, 0) {
+        report
+            .events
+            .push(OrderEvent::rejected(&new_order, RejectReason::Invalid(err)));
+        return;
+    }
+    new_order.locked = required_lock(state, &new_order);
+    let token = &state.config.paying_token(new_order.order_type).address;
+    state
+        .balances
+        .lock(&new_order.address, token, new_order.locked);
+    if state.config.matching_mode == MatchingMode::BatchAuction {
+        // It waits for the auction at the end of the batch, even if it crosses
+        report.events.push(OrderEvent::Rested {
+            order_id: new_order.id.clone(),
+            trader: new_order.address.clone(),
+            price: new_order.resting_price(),
+            quantity: new_order.quantity,
+        });
+        rest_order(state, new_order);
+        return;
+    }
+    let order_type = new_order.order_type;
+    let opposite = order_type.opposite();
+    let mut matching_orders = std::mem::replace(state.side_mut(opposite), BookSide::new(opposite));
+    process_order(
+        state,
+        &mut new_order,
+        &mut matching_orders,
+        order_type,
+        report,
+        policy,
+    );
+    *state.side_mut(opposite) = matching_orders;
+}
+
+/// Fills `new_order` against the opposite side of the book, best price first, sharing it among
+/// the orders at each price as `policy` allocates. Sweeps down the book while the prices cross
+/// and rests whatever is left of a limit order, unless its time in force says otherwise. The
+/// unfilled part of a market order is cancelled.
+fn process_order<P: MatchingPolicy>(
+    state: &mut State,
+    new_order: &mut Order,
+    matching_orders: &mut BookSide,
+    order_type: OrderType,
+    report: &mut ActionReport,
+    policy: &P,
+) {
+    if let Some(post_only) = new_order.post_only {
+        let best = matching_orders.best_price();
+        let outcome = post_only_outcome(
+            new_order,
+            post_only,
+            best,
+            order_type,
+            state.config.tick_size,
+        );
+        report.post_only = Some(outcome);
+        match outcome {
+            PostOnlyOutcome::Rejected => {
+                release_all(state, new_order);
+                report
+                    .events
+                    .push(OrderEvent::rejected(new_order, RejectReason::PostOnly));
+                return;
+            }
+            PostOnlyOutcome::Repriced(price) => new_order.price = Some(price),
+            PostOnlyOutcome::Accepted => {}
+        }
+    }
+
+    let limit = match (
+        new_order.price,
+        new_order.max_slippage_bps,
+        matching_orders.best_price(),
+    ) {
+        (None, Some(bps), Some(best)) => Some(slippage_limit(order_type, best, bps)),
+        (price, _, _) => price,
+    };
+    let self_trade_prevention = new_order
+        .self_trade_prevention
+        .unwrap_or(state.config.self_trade_prevention);
+
+    if new_order.time_in_force == TimeInForce::FillOrKill {
+        let (available, cost) = fill_or_kill_check(
+            state,
+            new_order,
+            matching_orders,
+            order_type,
+            limit,
+            self_trade_prevention,
+            policy,
+        );
+        let bps = state.config.fees.rates_for(&new_order.address).taker_bps;
+        let affordable = order_type == OrderType::Ask
+            || !new_order.is_market()
+            || cost.saturating_add(fee(cost, bps)) <= new_order.locked;
+        if available < new_order.quantity || !affordable {
+            release_all(state, new_order);
+            report
+                .events
+                .push(OrderEvent::rejected(new_order, RejectReason::FillOrKill));
+            return;
+        }
+    }
+
+    'levels: while !new_order.quantity.is_zero() {
+        let Some(resting_price) = matching_orders.best_price() else {
+            break;
+        };
+        if limit.is_some_and(|limit| !crosses(order_type, limit, resting_price)) {
+            break;
+        }
+        let level = matching_orders
+            .level(resting_price)
+            .expect("best level exists");
+        let ids: Vec<String> = level.values().map(|order| order.id.clone()).collect();
+        let shown: Vec<Quantity> = level.values().map(|order| order.quantity).collect();
+        let shares = policy.allocate(&shown, new_order.quantity, state.config.lot_size);
+
+        // Whatever is left after the level's orders took their shares, such as a refilled
+        // iceberg slice, is allocated again on the next pass
+        for (id, share) in ids.iter().zip(shares) {
+            if share.is_zero() || new_order.quantity.is_zero() {
+                continue;
+            }
+            let matched_order = matching_orders.get_mut(id).expect("order was just listed");
+            if matched_order.address == new_order.address {
+                let zero = Quantity::zero(0);
+                let (taker_cancelled, maker_cancelled) = match self_trade_prevention {
+                    SelfTradePrevention::CancelNewest => (new_order.quantity, zero),
+                    SelfTradePrevention::CancelOldest => (zero, matched_order.total_quantity()),
+                    SelfTradePrevention::CancelBoth => {
+                        (new_order.quantity, matched_order.total_quantity())
+                    }
+                    SelfTradePrevention::DecrementAndCancel => {
+                        let smaller = new_order.quantity.min(matched_order.total_quantity());
+                        (smaller, smaller)
+                    }
+                };
+                let prevented = SelfTradePrevented {
+                    address: new_order.address.clone(),
+                    mode: self_trade_prevention,
+                    taker_order_id: new_order.id.clone(),
+                    maker_order_id: matched_order.id.clone(),
+                    taker_cancelled,
+                    maker_cancelled,
+                };
+                report
+                    .events
+                    .push(OrderEvent::SelfTradePrevented(prevented.clone()));
+                report.self_trades.push(prevented);
+                new_order.quantity -= taker_cancelled;
+                matched_order.reduce(maker_cancelled);
+                release_excess(state, matched_order);
+                if !maker_cancelled.is_zero() {
+                    report
+                        .events
+                        .push(OrderEvent::cancelled(matched_order, maker_cancelled));
+                }
+                if !taker_cancelled.is_zero() {
+                    report
+                        .events
+                        .push(OrderEvent::cancelled(new_order, taker_cancelled));
+                }
+                matching_orders.settle(id, || state.next_sequence());
+                // Either order may be gone or smaller, so the level is allocated again
+                continue 'levels;
+            }
+
+            let price = state
+                .config
+                .price_rule
+                .trade_price(new_order.price, resting_price);
+            let mut trade_quantity = share.min(new_order.quantity);
+            if order_type == OrderType::Bid && new_order.is_market() {
+                trade_quantity =
+                    affordable_quantity(&state.config, new_order, price, trade_quantity);
+                if trade_quantity.is_zero() {
+                    break 'levels;
+                }
+            }
+
+            let mut trade = Trade {
+                id: state.next_sequence(),
+                ask_order: if order_type == OrderType::Ask {
+                    new_order.clone()
+                } else {
+                    matched_order.clone()
+                },
+                bid_order: if order_type == OrderType::Bid {
+                    new_order.clone()
+                } else {
+                    matched_order.clone()
+                },
+                price,
+                quantity: trade_quantity,
+                taker_side: order_type,
+                maker_fee: 0,
+                taker_fee: 0,
+            };
+            let quote = state.config.quote_amount(price, trade_quantity);
+            trade.maker_fee = fee(
+                quote,
+                state
+                    .config
+                    .fees
+                    .rates_for(&matched_order.address)
+                    .maker_bps,
+            );
+            trade.taker_fee = fee(
+                quote,
+                state.config.fees.rates_for(&new_order.address).taker_bps,
+            );
+            if order_type == OrderType::Bid {
+                settle(state, new_order, matched_order, &trade);
+            } else {
+                settle(state, matched_order, new_order, &trade);
+            }
+            state.last_price = Some(trade.price);
+            state.trade_chain = chain_trades(state.trade_chain, std::slice::from_ref(&trade));
+
+            report.trades.push(trade);
+
+            matched_order.quantity -= trade_quantity;
+            new_order.quantity -= trade_quantity;
+            report
+                .events
+                .push(OrderEvent::fill(matched_order, trade_quantity));
+            report
+                .events
+                .push(OrderEvent::fill(new_order, trade_quantity));
+            if matched_order.total_quantity().is_zero() {
+                // Price improvement and rounding can leave something behind
+                release_all(state, matched_order);
+            }
+            matching_orders.settle(id, || state.next_sequence());
+        }
+    }
+
+    if new_order.quantity.is_zero() {
+        release_all(state, new_order);
+        return;
+    }
+    if new_order.rests() {
+        release_excess(state, new_order);
+        report.events.push(OrderEvent::Rested {
+            order_id: new_order.id.clone(),
+            trader: new_order.address.clone(),
+            price: new_order.resting_price(),
+            quantity: new_order.quantity,
+        });
+        rest_order(state, new_order.clone());
+    } else {
+        release_all(state, new_order);
+        report.events.push(cancel_event(new_order));
+    }
+}
+
+/// A resting order as `fill_or_kill_check` sees it.
+struct Queued {
+    own: bool,
+    shown: Quantity,
+    hidden: Quantity,
+    display: Option<Quantity>,
+}
+
+/// How much of a fill-or-kill `order` the matching loop would fill, and at least what that costs,
+/// since each fill is rounded up. Replays the loop on a copy of each crossing level, sharing it
+/// out with `policy` pass by pass: an order of the same trader that is given a share stops it
+/// unless self-trade prevention cancels the resting side, and an iceberg whose slice runs out
+/// refills at the back of its level, behind everything queued after it.
+fn fill_or_kill_check<P: MatchingPolicy>(
+    state: &State,
+    order: &Order,
+    side: &BookSide,
+    order_type: OrderType,
+    limit: Option<Price>,
+    self_trade_prevention: SelfTradePrevention,
+    policy: &P,
+) -> (Quantity, u128) {
+    let mut left = order.quantity;
+    let mut cost = 0u128;
+    for (price, level) in side.levels() {
+        if left.is_zero() || limit.is_some_and(|limit| !crosses(order_type, limit, *price)) {
+            break;
+        }
+        let mut queue: Vec<Queued> = level
+            .values()
+            .map(|resting| Queued {
+                own: resting.address == order.address,
+                shown: resting.quantity,
+                hidden: resting.hidden_quantity,
+                display: resting.display_quantity,
+            })
+            .collect();
+        while !left.is_zero() && !queue.is_empty() {
+            let shown: Vec<Quantity> = queue.iter().map(|queued| queued.shown).collect();
+            let shares = policy.allocate(&shown, left, state.config.lot_size);
+            let (mut kept, mut refilled) = (Vec::new(), Vec::new());
+            let mut entries = std::mem::take(&mut queue).into_iter().zip(shares);
+            while let Some((mut queued, share)) = entries.next() {
+                if share.is_zero() || left.is_zero() {
+                    kept.push(queued);
+                    continue;
+                }
+                if queued.own {
+                    if self_trade_prevention != SelfTradePrevention::CancelOldest {
+                        return (order.quantity - left, cost);
+                    }
+                    // The own order goes and the rest of the level is shared out again
+                    kept.extend(entries.by_ref().map(|(queued, _)| queued));
+                    break;
+                }
+                let take = share.min(left);
+                left -= take;
+                cost = cost.saturating_add(state.config.cost(*price, take, true));
+                queued.shown -= take;
+                if !queued.shown.is_zero() {
+                    kept.push(queued);
+                } else if !queued.hidden.is_zero() {
+                    queued.shown = queued.display.unwrap_or(queued.hidden).min(queued.hidden);
+                    queued.hidden -= queued.shown;
+                    refilled.push(queued);
+                }
+            }
+            queue = kept.into_iter().chain(refilled).collect();
+        }
+    }
+    (order.quantity - left, cost)
+}
+
+/// Moves a trade's funds: the buyer pays the quote amount plus its fee out of its lock and
+/// receives the base, and the seller gives up the base out of its lock and receives the quote
+/// amount less its fee. The fee collector receives both fees.
+fn settle(state: &mut State, buyer: &mut Order, seller: &mut Order, trade: &Trade) {
+    let config = &state.config;
+    let (base, quote) = (
+        config.base_amount(trade.quantity),
+        config.quote_amount(trade.price, trade.quantity),
+    );
+    let (buyer_fee, seller_fee) = match trade.taker_side {
+        OrderType::Bid => (trade.taker_fee, trade.maker_fee),
+        OrderType::Ask => (trade.maker_fee, trade.taker_fee),
+    };
+    // Rates above 100% cannot take more than the seller receives
+    let seller_fee = seller_fee.min(quote);
+    let balances = &mut state.balances;
+    balances.spend_locked(&buyer.address, &config.quote.address, quote + buyer_fee);
+    buyer.locked -= quote + buyer_fee;
+    balances.credit(&buyer.address, &config.base.address, base);
+    balances.spend_locked(&seller.address, &config.base.address, base);
+    seller.locked -= base;
+    balances.credit(&seller.address, &config.quote.address, quote - seller_fee);
+    balances.credit(
+        &config.fees.collector,
+        &config.quote.address,
+        buyer_fee + seller_fee,
+    );
+    state.collected_fees += buyer_fee + seller_fee;
+}
+
+/// The most of `wanted` that a market bid can still pay for at `price`, taker fee included, out
+/// of what it has locked, in whole lots.
+fn affordable_quantity(
+    config: &MarketConfig,
+    order: &Order,
+    price: Price,
+    wanted: Quantity,
+) -> Quantity {
+    let bps = config.fees.rates_for(&order.address).taker_bps;
+    let affordable = |lots| {
+        let quote = config.quote_amount(price, Quantity::new(lots, wanted.scale));
+        quote.saturating_add(fee(quote, bps)) <= order.locked
+    };
+    let (mut low, mut high) = (0, wanted.lots);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if affordable(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    let affordable = Quantity::new(low, wanted.scale);
+    config
+        .lot_size
+        .map_or(affordable, |lot| affordable.round_down_to(lot))
+}
+
+fn post_only_outcome(
+    order: &Order,
+    post_only: PostOnly,
+    best: Option<Price>,
+    order_type: OrderType,
+    tick_size: Option<Price>,
+) -> PostOnlyOutcome {
+    let Some(price) = order.price else {
+        // A market order can only take liquidity
+        return PostOnlyOutcome::Rejected;
+    };
+    let Some(best_price) = best else {
+        return PostOnlyOutcome::Accepted;
+    };
+    if !crosses(order_type, price, best_price) {
+        return PostOnlyOutcome::Accepted;
+    }
+    match post_only {
+        PostOnly::Reject => PostOnlyOutcome::Rejected,
+        PostOnly::Reprice => {
+            // Without a tick size, a tick is the last decimal place of the order's own price
+            let tick = tick_size
+                .filter(|tick| !tick.is_zero())
+                .unwrap_or(Price::new(1, price.scale));
+            // A tick finer than the market's prices leaves nowhere to go
+            one_tick_away(order_type, best_price, tick)
+                .and_then(|repriced| repriced.to_scale(price.scale))
+                .map_or(PostOnlyOutcome::Rejected, PostOnlyOutcome::Repriced)
+        }
+    }
+}
+
+/// The closest multiple of `tick` that no longer crosses `best`: just below it for a bid, just
+/// above it for an ask.
+fn one_tick_away(order_type: OrderType, best: Price, tick: Price) -> Option<Price> {
+    // `best` expressed in ticks, as a fraction num / den
+    let num = best.ticks as u128 * 10u128.pow(tick.scale as u32);
+    let den = 10u128.pow(best.scale as u32) * tick.ticks as u128;
+    let ticks = match order_type {
+        OrderType::Bid => num
+            .div_ceil(den)
+            .checked_sub(1)
+            .filter(|ticks| *ticks > 0)?,
+        OrderType::Ask => num / den + 1,
+    };
+    Some(Price::new(
+        u64::try_from(ticks * tick.ticks as u128).ok()?,
+        tick.scale,
+    ))
+}
+
+/// Whether an incoming order with limit `price` can trade against a resting order at
+/// `resting_price`.
+fn crosses(order_type: OrderType, price: Price, resting_price: Price) -> bool {
+    match order_type {
+        OrderType::Bid => price >= resting_price,
+        OrderType::Ask => price <= resting_price,
+    }
+}
+
+/// The worst price a capped market order may reach, `bps` basis points past `best`. Falls back to
+/// `best` itself when the bound cannot be represented exactly.
+fn slippage_limit(order_type: OrderType, best: Price, bps: u32) -> Price {
+    let factor = match order_type {
+        OrderType::Bid => 10_000 + bps as u64,
+        OrderType::Ask => 10_000u64.saturating_sub(bps as u64),
+    };
+    best.ticks
+        .checked_mul(factor)
+        .filter(|_| best.scale + 4 <= MAX_SCALE)
+        .map(|ticks| Price::new(ticks, best.scale + 4))
+        .unwrap_or(best)
+}
+
+fn rest_order(state: &mut State, mut order: Order) {
+    order.split_display();
+    state.side_mut(order.order_type).insert(order);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_primitives::U256;
+    use alloy_sol_types::SolType;
+
+    /// A well-formed address derived from the order id, so each id trades as its own trader.
+    fn address(id: &str) -> String {
+        let hex: String = id.bytes().map(|b| format!("{:02x}", b)).collect();
+        format!("0x{:0>40}", hex)
+    }
+
+    fn order(id: &str, order_type: OrderType, price: &str, quantity: u64) -> Order {
+        Order::limit(
+            id,
+            &address(id),
+            order_type,
+            price.parse().unwrap(),
+            Quantity::new(quantity, 0),
+        )
+    }
+
+    fn market(id: &str, order_type: OrderType, quantity: u64) -> Order {
+        Order::market(id, &address(id), order_type, Quantity::new(quantity, 0))
+    }
+
+    /// Plenty of both tokens for any test order.
+    const FUNDS: u128 = 1_000_000 * 10u128.pow(MAX_SCALE as u32);
+
+    fn fund(state: &mut State, address: &str) {
+        for token in [
+            state.config.base.address.clone(),
+            state.config.quote.address.clone(),
+        ] {
+            state.balances.deposit(address, &token, FUNDS).unwrap();
+        }
+    }
+
+    /// Funds the order's trader the first time it appears, then places the order.
+    fn place(state: &mut State, order: Order) -> ActionReport {
+        place_at(state, order, 0)
+    }
+
+    fn place_at(state: &mut State, order: Order, timestamp: u64) -> ActionReport {
+        if state
+            .balances
+            .get(&order.address, &state.config.quote.address)
+            .total()
+            == 0
+        {
+            fund(state, &order.address);
+        }
+        let allocation = state.config.allocation;
+        match_order(state, order, &MatchContext { timestamp }, &allocation)
+            .expect("order should be accepted")
+    }
+
+    fn amend(id: &str, price: Option<&str>, quantity: Option<u64>) -> Action {
+        Action::Amend {
+            id: id.to_string(),
+            price: price.map(|price| price.parse().unwrap()),
+            quantity: quantity.map(|quantity| Quantity::new(quantity, 0)),
+        }
+    }
+
+    #[test]
+    fn test_bid_sweeps_crossing_ask_levels() {
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.07", 100));
+        place(&mut state, order("a2", OrderType::Ask, "1.05", 100));
+        place(&mut state, order("a3", OrderType::Ask, "1.05", 100));
+        place(&mut state, order("a4", OrderType::Ask, "1.20", 100));
+
+        // A bid at 1.10 takes both 1.05 orders oldest first, then the 1.07 level
+        let trades = place(&mut state, order("b1", OrderType::Bid, "1.10", 350)).trades;
+
+        let filled: Vec<&str> = trades.iter().map(|t| t.ask_order.id.as_str()).collect();
+        assert_eq!(filled, vec!["a2", "a3", "a1"]);
+        assert!(trades.iter().all(|t| t.quantity == Quantity::new(100, 0)));
+
+        // The remainder rests, and the book is not crossed
+        assert_eq!(state.bids.len(), 1);
+        assert_eq!(
+            state.bids.best_order().unwrap().quantity,
+            Quantity::new(50, 0)
+        );
+        assert_eq!(state.asks.len(), 1);
+        assert_eq!(state.asks.best_order().unwrap().id, "a4");
+    }
+
+    #[test]
+    fn test_fills_every_resting_order_at_one_level() {
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.05", 30));
+        place(&mut state, order("a2", OrderType::Ask, "1.05", 30));
+        place(&mut state, order("a3", OrderType::Ask, "1.05", 30));
+
+        let trades = place(&mut state, order("b1", OrderType::Bid, "1.05", 70)).trades;
+
+        // One trade per counterparty, the last one only partially consumed
+        let fills: Vec<(&str, Quantity)> = trades
+            .iter()
+            .map(|t| (t.ask_order.id.as_str(), t.quantity))
+            .collect();
+        let lots = |lots| Quantity::new(lots, 0);
+        assert_eq!(
+            fills,
+            vec![("a1", lots(30)), ("a2", lots(30)), ("a3", lots(10))]
+        );
+
+        // The incoming bid was fully filled, so nothing of it rests
+        assert!(state.bids.is_empty());
+        assert_eq!(state.asks.len(), 1);
+        assert_eq!(state.asks.best_order().unwrap().id, "a3");
+        assert_eq!(
+            state.asks.best_order().unwrap().quantity,
+            Quantity::new(20, 0)
+        );
+    }
+
+    #[test]
+    fn test_non_crossing_order_rests() {
+        let mut state = State::new();
+        place(&mut state, order("b1", OrderType::Bid, "1.00", 10));
+        place(&mut state, order("b2", OrderType::Bid, "1.02", 10));
+        let report = place(&mut state, order("a1", OrderType::Ask, "1.03", 10));
+
+        assert!(
+            report.trades.is_empty(),
+            "Orders that do not cross must not trade"
+        );
+        let bids: Vec<&str> = state.bids.orders().map(|o| o.id.as_str()).collect();
+        assert_eq!(bids, vec!["b2", "b1"], "Bids are kept best price first");
+        assert_eq!(state.asks.len(), 1);
+    }
+
+    #[test]
+    fn test_market_order_takes_liquidity_and_cancels_remainder() {
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.05", 100));
+        place(&mut state, order("a2", OrderType::Ask, "1.50", 100));
+
+        let trades = place(&mut state, market("b1", OrderType::Bid, 250)).trades;
+
+        // Trades execute at each resting order's price
+        let fills: Vec<(Price, Quantity)> = trades.iter().map(|t| (t.price, t.quantity)).collect();
+        let lots = Quantity::new(100, 0);
+        assert_eq!(
+            fills,
+            vec![(Price::new(105, 2), lots), (Price::new(150, 2), lots)]
+        );
+
+        // The unfilled 50 is cancelled rather than rested
+        assert!(state.bids.is_empty());
+        assert!(state.asks.is_empty());
+    }
+
+    #[test]
+    fn test_market_order_respects_slippage_cap() {
+        let mut state = State::new();
+        place(&mut state, order("b1", OrderType::Bid, "2.00", 10));
+        place(&mut state, order("b2", OrderType::Bid, "1.90", 10));
+        place(&mut state, order("b3", OrderType::Bid, "1.80", 10));
+
+        // 500 bps below the best bid of 2.00 is 1.90, so the 1.80 bid is out of reach
+        let mut sell = market("a1", OrderType::Ask, 30);
+        sell.max_slippage_bps = Some(500);
+        let report = place(&mut state, sell);
+
+        assert_eq!(report.trades.len(), 2);
+        assert_eq!(state.bids.len(), 1);
+        assert_eq!(state.bids.best_order().unwrap().id, "b3");
+        assert!(state.asks.is_empty(), "Market orders never rest");
+    }
+
+    #[test]
+    fn test_cancel_and_amend() {
+        let ctx = MatchContext::default();
+        let mut state = State::new();
+        place(&mut state, order("b1", OrderType::Bid, "1.00", 10));
+        place(&mut state, order("b2", OrderType::Bid, "1.00", 10));
+        place(&mut state, order("b3", OrderType::Bid, "0.90", 10));
+
+        apply_action(
+            &mut state,
+            Action::Cancel {
+                id: "b3".to_string(),
+            },
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(state.bids.len(), 2);
+
+        // Reducing the quantity keeps b1 ahead of b2
+        apply_action(&mut state, amend("b1", None, Some(5)), &ctx).unwrap();
+        let bids: Vec<(&str, Quantity)> = state
+            .bids
+            .orders()
+            .map(|o| (o.id.as_str(), o.quantity))
+            .collect();
+        assert_eq!(
+            bids,
+            vec![("b1", Quantity::new(5, 0)), ("b2", Quantity::new(10, 0))]
+        );
+
+        // Moving the price away and back again loses priority
+        apply_action(&mut state, amend("b1", Some("0.99"), None), &ctx).unwrap();
+        apply_action(&mut state, amend("b1", Some("1.00"), None), &ctx).unwrap();
+        let bids: Vec<&str> = state.bids.orders().map(|o| o.id.as_str()).collect();
+        assert_eq!(bids, vec!["b2", "b1"]);
+    }
+
+    #[test]
+    fn test_amend_into_the_spread_matches() {
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.05", 10));
+        place(&mut state, order("b1", OrderType::Bid, "1.00", 10));
+
+        let report = apply_action(
+            &mut state,
+            amend("b1", Some("1.05"), None),
+            &MatchContext::default(),
+        )
+        .unwrap();
+
+        assert_eq!(report.trades.len(), 1);
+        assert!(state.bids.is_empty());
+        assert!(state.asks.is_empty());
+    }
+
+    #[test]
+    fn test_immediate_or_cancel_and_fill_or_kill() {
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.05", 100));
+
+        // Fill-or-kill for more than is available does nothing at all
+        let mut fok = order("b1", OrderType::Bid, "1.05", 150);
+        fok.time_in_force = TimeInForce::FillOrKill;
+        let report = place(&mut state, fok);
+        assert!(report.trades.is_empty());
+        assert!(state.bids.is_empty());
+        assert_eq!(
+            state.asks.best_order().unwrap().quantity,
+            Quantity::new(100, 0)
+        );
+
+        // Immediate-or-cancel takes the 100 and drops the other 50
+        let mut ioc = order("b2", OrderType::Bid, "1.05", 150);
+        ioc.time_in_force = TimeInForce::ImmediateOrCancel;
+        let report = place(&mut state, ioc);
+        assert_eq!(report.trades.len(), 1);
+        assert!(state.bids.is_empty());
+        assert!(state.asks.is_empty());
+    }
+
+    #[test]
+    fn test_fill_or_kill_follows_the_queue_past_an_iceberg_refill() {
+        let mut state = State::new();
+        let mut iceberg = order("a1", OrderType::Ask, "1.00", 20);
+        iceberg.display_quantity = Some(Quantity::new(5, 0));
+        place(&mut state, iceberg);
+        let mut own = order("a2", OrderType::Ask, "1.00", 10);
+        own.address = address("b1");
+        place(&mut state, own);
+
+        // The iceberg shows 5, then refills behind b1's own ask, where cancel-newest would stop
+        // the bid after 5 of 20
+        let mut fok = order("b1", OrderType::Bid, "1.00", 20);
+        fok.time_in_force = TimeInForce::FillOrKill;
+        let before = state.clone();
+        let report = place(&mut state, fok.clone());
+        assert!(report.trades.is_empty());
+        assert!(report.self_trades.is_empty());
+        assert_eq!(
+            report.events.last(),
+            Some(&OrderEvent::rejected(&fok, RejectReason::FillOrKill))
+        );
+        assert_eq!(state.asks, before.asks);
+    }
+
+    #[test]
+    fn test_good_til_time_expires() {
+        let mut state = State::new();
+        let mut gtt = order("a1", OrderType::Ask, "1.05", 100);
+        gtt.time_in_force = TimeInForce::GoodTilTime(1_000);
+        place_at(&mut state, gtt, 900);
+        assert_eq!(state.asks.len(), 1);
+
+        // Once the clock reaches the expiry the order is gone before the bid can match it
+        fund(&mut state, &address("b1"));
+        let bid = Action::Place(order("b1", OrderType::Bid, "1.05", 100));
+        let report = apply_action(&mut state, bid, &MatchContext { timestamp: 1_000 }).unwrap();
+        assert!(report.trades.is_empty());
+        assert!(state.asks.is_empty());
+        assert_eq!(state.bids.len(), 1);
+    }
+
+    #[test]
+    fn test_post_only_never_takes_liquidity() {
+        // Prices at two decimal places, so a tick is 0.01
+        let mut state = State::with_config(MarketConfig {
+            price_decimals: 2,
+            ..MarketConfig::default()
+        });
+        place(&mut state, order("a1", OrderType::Ask, "1.05", 100));
+
+        let mut reject = order("b1", OrderType::Bid, "1.05", 10);
+        reject.post_only = Some(PostOnly::Reject);
+        let report = place(&mut state, reject);
+        assert_eq!(report.post_only, Some(PostOnlyOutcome::Rejected));
+        assert!(state.bids.is_empty());
+
+        let mut reprice = order("b2", OrderType::Bid, "1.10", 10);
+        reprice.post_only = Some(PostOnly::Reprice);
+        let report = place(&mut state, reprice);
+        assert_eq!(
+            report.post_only,
+            Some(PostOnlyOutcome::Repriced(Price::new(104, 2)))
+        );
+        assert_eq!(
+            state.bids.best_order().unwrap().price,
+            Some(Price::new(104, 2))
+        );
+
+        let mut passive = order("b3", OrderType::Bid, "1.00", 10);
+        passive.post_only = Some(PostOnly::Reject);
+        let report = place(&mut state, passive);
+        assert_eq!(report.post_only, Some(PostOnlyOutcome::Accepted));
+        assert_eq!(
+            state.trade_chain, [0; 32],
+            "Post-only orders never trade on arrival"
+        );
+        assert_eq!(state.bids.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_orders_are_rejected_without_side_effects() {
+        let ctx = MatchContext { timestamp: 1_000 };
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.05", 100));
+        let before = state.clone();
+
+        let mut bad_address = order("b1", OrderType::Bid, "1.05", 10);
+        bad_address.address = "123".to_string();
+        let mut uppercase = order("b5", OrderType::Bid, "1.05", 10);
+        uppercase.address = format!("0x{}", "AB".repeat(20));
+        let mut expired = order("b2", OrderType::Bid, "1.05", 10);
+        expired.time_in_force = TimeInForce::GoodTilTime(999);
+
+        let cases = vec![
+            (
+                order("", OrderType::Bid, "1.05", 10),
+                MatchError::EmptyOrderId,
+            ),
+            (
+                order("a1", OrderType::Bid, "1.05", 10),
+                MatchError::DuplicateOrderId("a1".to_string()),
+            ),
+            (bad_address, MatchError::InvalidAddress("123".to_string())),
+            (
+                uppercase,
+                MatchError::InvalidAddress(format!("0x{}", "AB".repeat(20))),
+            ),
+            (
+                order("b3", OrderType::Bid, "1.05", 0),
+                MatchError::ZeroQuantity,
+            ),
+            (order("b4", OrderType::Bid, "0", 10), MatchError::ZeroPrice),
+            (expired, MatchError::AlreadyExpired),
+        ];
+        for (order, error) in cases {
+            assert_eq!(match_order(&mut state, order, &ctx, &PriceTime), Err(error));
+            assert_eq!(state, before, "A rejected order must not change the book");
+        }
+
+        let cancel = Action::Cancel {
+            id: "missing".to_string(),
+        };
+        assert_eq!(
+            apply_action(&mut state, cancel, &ctx),
+            Err(MatchError::UnknownOrderId("missing".to_string()))
+        );
+        assert_eq!(
+            apply_action(&mut state, amend("a1", None, Some(0)), &ctx),
+            Err(MatchError::ZeroQuantity)
+        );
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn test_orders_are_held_at_the_market_scale() {
+        let ctx = MatchContext::default();
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.05", 20));
+        let resting = state.asks.get("a1").unwrap();
+        assert_eq!(resting.resting_price().scale, state.config.price_decimals);
+        assert_eq!(resting.quantity.scale, state.config.quantity_decimals);
+        let before = state.clone();
+
+        // Finer than the market's eight decimal places, though the base token has eighteen
+        let dust = Order {
+            quantity: Quantity::new(1, 18),
+            ..order("b1", OrderType::Bid, "1.05", 0)
+        };
+        let fine_price = order("b2", OrderType::Bid, "1.000000001", 1);
+        let huge = Order {
+            quantity: Quantity::new(u64::MAX, 0),
+            ..order("b3", OrderType::Bid, "1.05", 0)
+        };
+        let cases = [
+            (dust, MatchError::TooPrecise(Quantity::new(1, 18))),
+            (
+                fine_price,
+                MatchError::PriceTooPrecise("1.000000001".parse().unwrap()),
+            ),
+            (huge, MatchError::OutOfRange),
+        ];
+        for (order, error) in cases {
+            assert_eq!(match_order(&mut state, order, &ctx, &PriceTime), Err(error));
+            assert_eq!(state, before);
+        }
+        let amend = Action::Amend {
+            id: "a1".to_string(),
+            price: None,
+            quantity: Some(Quantity::new(1, 18)),
+        };
+        assert_eq!(
+            apply_action(&mut state, amend, &ctx),
+            Err(MatchError::TooPrecise(Quantity::new(1, 18)))
+        );
+
+        // Trailing zeros beyond the market's scale are harmless
+        let mut bid = order("b4", OrderType::Bid, "1.05", 0);
+        bid.quantity = Quantity::new(10u64.pow(18), 18);
+        assert_eq!(
+            place(&mut state, bid).trades[0].quantity,
+            Quantity::new(1, 0)
+        );
+    }
+
+    #[test]
+    fn test_book_side_levels_and_serialization() {
+        let mut state = State::new();
+        place(&mut state, order("b1", OrderType::Bid, "1.00", 10));
+        place(&mut state, order("b2", OrderType::Bid, "1.02", 10));
+        place(&mut state, order("b3", OrderType::Bid, "1.000", 10));
+
+        // 1.00 and 1.000 are the same level, and bids iterate from the highest price
+        let levels: Vec<(Price, usize)> = state
+            .bids
+            .levels()
+            .map(|(price, level)| (*price, level.len()))
+            .collect();
+        assert_eq!(levels, vec![(Price::new(102, 2), 1), (Price::new(1, 0), 2)]);
+        assert!(levels
+            .iter()
+            .all(|(price, _)| price.scale == state.config.price_decimals));
+        assert_eq!(state.bids.best_price(), Some(Price::new(102, 2)));
+
+        let json = serde_json::to_string(&state).unwrap();
+        let decoded: State = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, state);
+        assert_eq!(
+            serde_json::to_string(&decoded).unwrap(),
+            json,
+            "Encoding must be deterministic"
+        );
+    }
+
+    #[test]
+    fn test_self_trade_prevention_modes() {
+        // (mode, quantity of the own bid left resting, trades)
+        let cases = vec![
+            (SelfTradePrevention::CancelNewest, Some(10), 0),
+            (SelfTradePrevention::CancelOldest, None, 1),
+            (SelfTradePrevention::CancelBoth, None, 0),
+            (SelfTradePrevention::DecrementAndCancel, Some(4), 0),
+        ];
+        for (mode, own_bid_left, trades) in cases {
+            let mut state = State::with_config(MarketConfig {
+                self_trade_prevention: mode,
+                ..Default::default()
+            });
+            let mut own_bid = order("b1", OrderType::Bid, "1.05", 10);
+            own_bid.address = address("same");
+            place(&mut state, own_bid);
+            place(&mut state, order("b2", OrderType::Bid, "1.05", 6));
+
+            let mut sell = order("a1", OrderType::Ask, "1.05", 6);
+            sell.address = address("same");
+            let report = place(&mut state, sell);
+
+            let own_bid = state.bids.get("b1").map(|o| o.quantity);
+            assert_eq!(
+                own_bid,
+                own_bid_left.map(|lots| Quantity::new(lots, 0)),
+                "own bid after {:?}",
+                mode
+            );
+            assert!(
+                state.asks.is_empty(),
+                "the incoming ask never rests after {:?}",
+                mode
+            );
+            assert_eq!(report.trades.len(), trades, "trades after {:?}", mode);
+
+            // Every prevented match is reported, and no trade ever pairs an address with itself
+            assert_eq!(report.self_trades.len(), 1);
+            assert_eq!(report.self_trades[0].maker_order_id, "b1");
+            // Batches keep only the events, which say why the orders were cancelled
+            let events: Vec<&OrderEvent> = report
+                .events
+                .iter()
+                .skip_while(|event| !matches!(event, OrderEvent::SelfTradePrevented(_)))
+                .collect();
+            assert_eq!(
+                events[0],
+                &OrderEvent::SelfTradePrevented(report.self_trades[0].clone())
+            );
+            assert!(
+                matches!(events[1], OrderEvent::Cancelled { .. }),
+                "after {:?}",
+                mode
+            );
+            assert!(report
+                .trades
+                .iter()
+                .all(|t| t.ask_order.address != t.bid_order.address));
+        }
+    }
+
+    #[test]
+    fn test_one_trader_has_one_address_form() {
+        let mut state = State::new();
+        let lower = address("same");
+        let upper = lower.to_uppercase().replacen("0X", "0x", 1);
+        let mut ask = order("a1", OrderType::Ask, "1.05", 10);
+        ask.address = lower.clone();
+        place(&mut state, ask);
+
+        // The uppercase form cannot act as a second trader beside the lowercase one
+        let ctx = MatchContext::default();
+        let invalid = Err(MatchError::InvalidAddress(upper.clone()));
+        let mut bid = order("b1", OrderType::Bid, "1.05", 10);
+        bid.address = upper.clone();
+        let deposit = Action::Deposit {
+            address: upper.clone(),
+            token: "QUOTE".to_string(),
+            amount: 1,
+        };
+        assert_eq!(
+            match_order(&mut state, bid.clone(), &ctx, &PriceTime).map(|_| ()),
+            invalid
+        );
+        assert_eq!(apply_action(&mut state, deposit, &ctx).map(|_| ()), invalid);
+        assert_eq!(
+            apply_action(&mut state, Action::CancelAll { address: upper }, &ctx).map(|_| ()),
+            invalid
+        );
+
+        // Sent in the one form it has, the bid meets self-trade prevention
+        bid.address = lower;
+        let report = place(&mut state, bid);
+        assert!(report.trades.is_empty());
+        assert_eq!(report.self_trades.len(), 1);
+    }
+
+    #[test]
+    fn test_order_overrides_market_self_trade_mode() {
+        let mut state = State::new();
+        let mut own_bid = order("b1", OrderType::Bid, "1.05", 10);
+        own_bid.address = address("same");
+        place(&mut state, own_bid);
+        place(&mut state, order("b2", OrderType::Bid, "1.05", 10));
+
+        let mut sell = order("a1", OrderType::Ask, "1.05", 10);
+        sell.address = address("same");
+        sell.self_trade_prevention = Some(SelfTradePrevention::CancelOldest);
+        let trades = place(&mut state, sell).trades;
+
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].bid_order.id, "b2");
+        assert!(state.bids.is_empty());
+    }
+
+    #[test]
+    fn test_fees_charged_to_maker_and_taker() {
+        let mut config = MarketConfig::default();
+        config.fees.default = FeeRates {
+            maker_bps: 10,
+            taker_bps: 25,
+        };
+        config.fees.tiers.insert(
+            address("vip"),
+            FeeRates {
+                maker_bps: 0,
+                taker_bps: 5,
+            },
+        );
+        config.fees.collector = address("fees");
+        // With two decimals on the quote token, fees come out in the same units as the notional
+        config.quote.decimals = 2;
+        let mut state = State::with_config(config);
+
+        place(&mut state, order("a1", OrderType::Ask, "2.00", 1_000));
+        let first = place(&mut state, order("b1", OrderType::Bid, "2.00", 500))
+            .trades
+            .remove(0);
+        let mut vip = order("b2", OrderType::Bid, "2.00", 500);
+        vip.address = address("vip");
+        let second = place(&mut state, vip).trades.remove(0);
+
+        // Notional is 2.00 * 500 = 1000.00, or 100_000 units of a two-decimal quote token
+        assert_eq!(
+            state.config.quote_amount(first.price, first.quantity),
+            100_000
+        );
+        assert_eq!(first.taker_side, OrderType::Bid);
+        assert_eq!((first.maker_fee, first.taker_fee), (100, 250));
+        assert_eq!(
+            (second.maker_fee, second.taker_fee),
+            (100, 50),
+            "vip tier applies to the taker"
+        );
+
+        assert_eq!(state.collected_fees, 500);
+        assert_eq!(state.balances.get(&address("fees"), "QUOTE").available, 500);
+
+        // A market that charges fees needs somewhere valid to put them
+        state.config.fees.collector = String::new();
+        let before = state.clone();
+        let err = match_order(
+            &mut state,
+            order("b3", OrderType::Bid, "2.00", 1),
+            &MatchContext::default(),
+            &PriceTime,
+        );
+        assert_eq!(err, Err(MatchError::InvalidAddress(String::new())));
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn test_stop_orders_trigger_and_cascade() {
+        let mut state = State::new();
+        place(&mut state, order("b1", OrderType::Bid, "1.00", 10));
+        place(&mut state, order("b2", OrderType::Bid, "0.95", 10));
+        place(&mut state, order("b3", OrderType::Bid, "0.90", 10));
+
+        // A stop-market sell at 1.00 and a stop-limit sell that triggers at 0.95
+        let mut stop_market = market("s1", OrderType::Ask, 10);
+        stop_market.trigger = Some("1.00".parse().unwrap());
+        let mut stop_limit = order("s2", OrderType::Ask, "0.90", 10);
+        stop_limit.trigger = Some("0.95".parse().unwrap());
+        place(&mut state, stop_market);
+        place(&mut state, stop_limit);
+        assert_eq!(
+            state.stop_orders.len(),
+            2,
+            "No trade has happened, so nothing triggers"
+        );
+
+        // Selling into the 1.00 bid triggers s1, whose fill at 0.95 then triggers s2
+        let report = place(&mut state, order("a1", OrderType::Ask, "1.00", 10));
+        assert_eq!(report.triggered_stops, vec!["s1", "s2"]);
+        assert!(state.stop_orders.is_empty());
+
+        let fills: Vec<(&str, &str)> = report
+            .trades
+            .iter()
+            .map(|t| (t.ask_order.id.as_str(), t.bid_order.id.as_str()))
+            .collect();
+        assert_eq!(fills, vec![("a1", "b1"), ("s1", "b2"), ("s2", "b3")]);
+        assert_eq!(state.last_price, Some("0.90".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_cancel_stop_order() {
+        let mut state = State::new();
+        let mut stop = market("s1", OrderType::Bid, 10);
+        stop.trigger = Some("1.10".parse().unwrap());
+        place(&mut state, stop);
+
+        apply_action(
+            &mut state,
+            Action::Cancel {
+                id: "s1".to_string(),
+            },
+            &MatchContext::default(),
+        )
+        .unwrap();
+        assert!(state.stop_orders.is_empty());
+    }
+
+    #[test]
+    fn test_iceberg_shows_slice_and_refills_to_back_of_queue() {
+        let mut state = State::new();
+        let mut iceberg = order("a1", OrderType::Ask, "1.00", 100);
+        iceberg.display_quantity = Some(Quantity::new(30, 0));
+        place(&mut state, iceberg);
+        place(&mut state, order("a2", OrderType::Ask, "1.00", 50));
+
+        let resting = state.asks.get("a1").unwrap();
+        assert_eq!(
+            resting.quantity,
+            Quantity::new(30, 0),
+            "Only the display slice is visible"
+        );
+        assert_eq!(resting.hidden_quantity, Quantity::new(70, 0));
+
+        // Taking the displayed slice refills a1 from its reserve behind a2
+        place(&mut state, order("b1", OrderType::Bid, "1.00", 30));
+        let queue: Vec<&str> = state.asks.orders().map(|o| o.id.as_str()).collect();
+        assert_eq!(queue, vec!["a2", "a1"]);
+        let refilled = state.asks.get("a1").unwrap();
+        assert_eq!(
+            (refilled.quantity, refilled.hidden_quantity),
+            (Quantity::new(30, 0), Quantity::new(40, 0))
+        );
+
+        // A large taker sweeps the visible and hidden quantity alike
+        let trades = place(&mut state, order("b2", OrderType::Bid, "1.00", 120)).trades;
+        assert!(state.asks.is_empty());
+        let filled = trades
+            .iter()
+            .fold(Quantity::zero(0), |total, t| total + t.quantity);
+        assert_eq!(filled, Quantity::new(120, 0));
+        assert!(
+            state.bids.is_empty(),
+            "The hidden reserve filled b2 completely"
+        );
+    }
+
+    #[test]
+    fn test_hidden_quantity_given_on_submission_is_ignored() {
+        let mut state = State::new();
+        let mut hidden = order("a1", OrderType::Ask, "1.00", 10);
+        hidden.hidden_quantity = Quantity::new(90, 0);
+        place(&mut state, hidden);
+        let resting = state.asks.get("a1").unwrap();
+        assert_eq!(
+            (resting.quantity, resting.hidden_quantity),
+            (Quantity::new(10, 0), Quantity::zero(0))
+        );
+        assert_eq!(
+            state.balances.get(&address("a1"), "BASE").locked,
+            10 * 10u128.pow(18)
+        );
+
+        // Would overflow the total if the reserve were counted
+        let mut huge = order("a2", OrderType::Ask, "1.00", 0);
+        huge.quantity = Quantity::new(u64::MAX, state.config.quantity_decimals);
+        huge.hidden_quantity = Quantity::new(1, 0);
+        let ctx = MatchContext::default();
+        assert!(matches!(
+            match_order(&mut state, huge, &ctx, &PriceTime),
+            Err(MatchError::InsufficientBalance { .. })
+        ));
+    }
+
+    #[test]
+    fn test_events_describe_each_order_lifecycle() {
+        let mut state = State::new();
+        let qty = |lots| Quantity::new(lots, 0);
+        let mut expiring = order("a2", OrderType::Ask, "1.10", 5);
+        expiring.time_in_force = TimeInForce::GoodTilTime(100);
+        let mut post_only = order("b2", OrderType::Bid, "1.00", 1);
+        post_only.post_only = Some(PostOnly::Reject);
+        let actions = vec![
+            Action::Place(order("a1", OrderType::Ask, "1.00", 10)),
+            Action::Place(expiring),
+            Action::Place(order("b1", OrderType::Bid, "1.00", 4)),
+            Action::Place(post_only),
+            Action::Place(order("", OrderType::Bid, "1.00", 1)),
+            Action::Cancel {
+                id: "a1".to_string(),
+            },
+        ];
+        for id in ["a1", "a2", "b1", "b2", ""] {
+            fund(&mut state, &address(id));
+        }
+        let ctx = MatchContext { timestamp: 50 };
+        let mut events = apply_actions(&mut state, &actions, &ctx).events;
+        events.extend(place_at(&mut state, order("b3", OrderType::Bid, "0.90", 1), 100).events);
+
+        let accepted = |id: &str, quantity| OrderEvent::Accepted {
+            order_id: id.to_string(),
+            trader: address(id),
+            quantity: qty(quantity),
+        };
+        let rested = |id: &str, price: &str, quantity| OrderEvent::Rested {
+            order_id: id.to_string(),
+            trader: address(id),
+            price: price.parse().unwrap(),
+            quantity: qty(quantity),
+        };
+        let rejected = |id: &str, reason| OrderEvent::Rejected {
+            order_id: id.to_string(),
+            trader: address(id),
+            quantity: qty(1),
+            reason,
+        };
+        assert_eq!(
+            events,
+            vec![
+                accepted("a1", 10),
+                rested("a1", "1.00", 10),
+                accepted("a2", 5),
+                rested("a2", "1.10", 5),
+                accepted("b1", 4),
+                OrderEvent::PartiallyFilled {
+                    order_id: "a1".to_string(),
+                    trader: address("a1"),
+                    filled: qty(4),
+                    remaining: qty(6)
+                },
+                OrderEvent::Filled {
+                    order_id: "b1".to_string(),
+                    trader: address("b1"),
+                    filled: qty(4)
+                },
+                accepted("b2", 1),
+                rejected("b2", RejectReason::PostOnly),
+                rejected("", RejectReason::Invalid(MatchError::EmptyOrderId)),
+                OrderEvent::Cancelled {
+                    order_id: "a1".to_string(),
+                    trader: address("a1"),
+                    cancelled: qty(6),
+                    remaining: qty(0)
+                },
+                OrderEvent::Expired {
+                    order_id: "a2".to_string(),
+                    trader: address("a2"),
+                    quantity: qty(5)
+                },
+                accepted("b3", 1),
+                rested("b3", "0.90", 1),
+            ]
+        );
+
+        assert_eq!(event_hash(&events), event_hash(&events.clone()));
+        assert_ne!(event_hash(&events), event_hash(&events[1..]));
+    }
+
+    #[test]
+    fn test_price_rule_decides_price_improvement() {
+        let price = |s: &str| s.parse::<Price>().unwrap();
+        let cases = [
+            (PriceRule::Maker, "1.00"),
+            (PriceRule::Taker, "1.05"),
+            (PriceRule::Midpoint, "1.025"),
+        ];
+        for (rule, expected) in cases {
+            let mut state = State::with_config(MarketConfig {
+                price_rule: rule,
+                ..MarketConfig::default()
+            });
+            place(&mut state, order("a1", OrderType::Ask, "1.00", 10));
+            // The bid is willing to pay up to 1.05 for an ask resting at 1.00
+            let trades = place(&mut state, order("b1", OrderType::Bid, "1.05", 10)).trades;
+            assert_eq!(trades[0].price, price(expected), "{:?}", rule);
+            assert_eq!(state.last_price, Some(price(expected)));
+        }
+
+        // Under the default rule a sweep pays each level's own price, not the taker's limit
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.00", 10));
+        place(&mut state, order("a2", OrderType::Ask, "1.02", 10));
+        let trades = place(&mut state, order("b1", OrderType::Bid, "1.05", 20)).trades;
+        let prices: Vec<Price> = trades.iter().map(|t| t.price).collect();
+        assert_eq!(prices, vec![price("1.00"), price("1.02")]);
+        let notional = |trade: &Trade| state.config.quote_amount(trade.price, trade.quantity);
+        assert_eq!(
+            trades.iter().map(notional).sum::<u128>(),
+            (100 * 10 + 102 * 10) * 10u128.pow(16)
+        );
+    }
+
+    #[test]
+    fn test_midpoint_rounds_towards_maker_at_max_scale() {
+        let taker = Price::new(3, MAX_SCALE);
+        assert_eq!(
+            midpoint(taker, Price::new(2, MAX_SCALE)),
+            Price::new(2, MAX_SCALE)
+        );
+        assert_eq!(
+            midpoint(taker, Price::new(4, MAX_SCALE)),
+            Price::new(4, MAX_SCALE)
+        );
+        assert_eq!(
+            midpoint(Price::new(3, 2), Price::new(2, 2)),
+            Price::new(25, 3)
+        );
+    }
+
+    #[test]
+    fn test_sequence_numbers_give_unique_trade_ids_and_priority() {
+        let mut state = State::new();
+        let mut iceberg = order("a1", OrderType::Ask, "1.00", 20);
+        iceberg.display_quantity = Some(Quantity::new(5, 0));
+        place(&mut state, iceberg);
+        // Repeated fills between the same pair of orders
+        let bids = [("b1", 3), ("b1-again", 2), ("b2", 3), ("b3", 3)];
+        let trades: Vec<Trade> = bids
+            .into_iter()
+            .flat_map(|(id, quantity)| {
+                place(&mut state, order(id, OrderType::Bid, "1.00", quantity)).trades
+            })
+            .collect();
+
+        let ids: Vec<u64> = trades.iter().map(|t| t.id).collect();
+        let mut unique = ids.clone();
+        unique.dedup();
+        assert_eq!(ids.len(), 5, "b3 fills across an iceberg refill");
+        assert_eq!(unique, ids);
+        assert!(
+            ids.windows(2).all(|pair| pair[0] < pair[1]),
+            "Trade ids increase"
+        );
+
+        // The refilled iceberg was re-queued under a newer sequence than it first had
+        let resting = state.asks.get("a1").unwrap();
+        assert!(resting.sequence > trades[0].ask_order.sequence);
+        assert_eq!(resting.sequence, state.asks.best_order().unwrap().sequence);
+
+        // A sequence supplied by the caller is ignored, so it cannot jump the queue
+        place(&mut state, order("a2", OrderType::Ask, "1.00", 5));
+        let mut sneaky = order("a3", OrderType::Ask, "1.00", 5);
+        sneaky.sequence = 0;
+        place(&mut state, sneaky);
+        let queue: Vec<&str> = state.asks.orders().map(|o| o.id.as_str()).collect();
+        assert_eq!(queue, vec!["a1", "a2", "a3"]);
+        assert_eq!(state.asks.get("a3").unwrap().sequence, state.sequence);
+    }
+
+    #[test]
+    fn test_top_of_book_and_depth() {
+        let mut state = State::new();
+        assert_eq!(
+            (state.best_bid(), state.spread(), state.mid_price()),
+            (None, None, None)
+        );
+        assert_eq!(state.depth(5), Depth::default());
+
+        place(&mut state, order("b1", OrderType::Bid, "0.99", 10));
+        place(&mut state, order("b2", OrderType::Bid, "0.99", 5));
+        place(&mut state, order("b3", OrderType::Bid, "0.98", 7));
+        let mut iceberg = order("a1", OrderType::Ask, "1.02", 100);
+        iceberg.display_quantity = Some(Quantity::new(4, 0));
+        place(&mut state, iceberg);
+        place(&mut state, order("a2", OrderType::Ask, "1.030", 3));
+
+        let price = |s: &str| s.parse::<Price>().unwrap();
+        assert_eq!(state.best_bid(), Some(price("0.99")));
+        assert_eq!(state.best_ask(), Some(price("1.02")));
+        assert_eq!(state.spread(), Some(price("0.03")));
+        assert_eq!(state.mid_price(), Some(price("1.005")));
+
+        let level = |p: &str, lots| DepthLevel {
+            price: price(p),
+            quantity: Quantity::new(lots, 0),
+        };
+        let depth = state.depth(1);
+        assert_eq!(depth.bids, vec![level("0.99", 15)]);
+        assert_eq!(
+            depth.asks,
+            vec![level("1.02", 4)],
+            "Only the iceberg's displayed slice counts"
+        );
+        let depth = state.depth(10);
+        assert_eq!(depth.bids, vec![level("0.99", 15), level("0.98", 7)]);
+        assert_eq!(depth.asks, vec![level("1.02", 4), level("1.03", 3)]);
+
+        // The checksum covers "0.99:15:1.02:4:0.98:7:1.03:3", with 1.030 written as 1.03
+        assert_eq!(
+            depth.checksum(),
+            depth::crc32(b"0.99:15:1.02:4:0.98:7:1.03:3")
+        );
+        let before = depth.checksum();
+        place(&mut state, order("b4", OrderType::Bid, "0.98", 1));
+        assert_ne!(
+            state.depth(10).checksum(),
+            before,
+            "Any change in the top levels shows up"
+        );
+        assert_eq!(state.depth(1).checksum(), state.depth(1).checksum());
+    }
+
+    #[test]
+    fn test_indexes_track_orders_and_cancel_all() {
+        let mut state = State::new();
+        let trader = address("trader");
+        let mine = |id: &str, order_type, price: &str, quantity| {
+            Order::limit(
+                id,
+                &trader,
+                order_type,
+                price.parse().unwrap(),
+                Quantity::new(quantity, 0),
+            )
+        };
+        place(&mut state, mine("t-bid", OrderType::Bid, "0.90", 10));
+        place(&mut state, mine("t-ask", OrderType::Ask, "1.10", 10));
+        place(&mut state, mine("t-filled", OrderType::Ask, "1.05", 10));
+        let mut stop = mine("t-stop", OrderType::Bid, "1.20", 5);
+        stop.trigger = Some("1.15".parse().unwrap());
+        place(&mut state, stop);
+        place(&mut state, order("other", OrderType::Ask, "1.10", 10));
+
+        assert_eq!(state.order("t-ask").unwrap().quantity, Quantity::new(10, 0));
+        assert_eq!(state.order("t-stop").unwrap().order_type, OrderType::Bid);
+        let open = |state: &State| {
+            state
+                .open_orders(&trader)
+                .iter()
+                .map(|o| o.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(open(&state), vec!["t-bid", "t-ask", "t-filled", "t-stop"]);
+
+        // Matching, amends and round-trips through serialization keep the indexes in step
+        place(&mut state, order("taker", OrderType::Bid, "1.05", 10));
+        apply_action(
+            &mut state,
+            amend("t-bid", Some("0.95"), None),
+            &MatchContext::default(),
+        )
+        .unwrap();
+        assert_eq!(open(&state), vec!["t-bid", "t-ask", "t-stop"]);
+        assert_eq!(
+            state.order("t-bid").unwrap().price,
+            Some("0.95".parse().unwrap())
+        );
+        assert!(state.order("t-filled").is_none());
+
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(!json.contains("locations"), "Indexes are not serialized");
+        let mut restored: State = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, state);
+        assert_eq!(open(&restored), open(&state));
+
+        let action = Action::CancelAll {
+            address: trader.clone(),
+        };
+        let report = apply_action(&mut restored, action, &MatchContext::default()).unwrap();
+        let cancelled: Vec<&str> = report
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                OrderEvent::Cancelled { order_id, .. } => Some(order_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(cancelled, vec!["t-bid", "t-ask", "t-stop"]);
+        assert!(open(&restored).is_empty());
+        assert!(restored.stop_orders.is_empty());
+        assert_eq!(
+            restored
+                .asks
+                .orders()
+                .map(|o| o.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["other"]
+        );
+    }
+
+    #[test]
+    fn test_trades_leave_state_and_extend_the_hash_chain() {
+        let mut state = State::new();
+        let actions = [
+            Action::Place(order("a1", OrderType::Ask, "1.00", 10)),
+            Action::Place(order("b1", OrderType::Bid, "1.00", 4)),
+            Action::Place(order("b2", OrderType::Bid, "1.00", 4)),
+        ];
+        for id in ["a1", "b1", "b2"] {
+            fund(&mut state, &address(id));
+        }
+        let first = apply_actions(&mut state, &actions[..2], &MatchContext::default());
+        let head = state.trade_chain;
+        let second = apply_actions(&mut state, &actions[2..], &MatchContext::default());
+
+        assert_eq!((first.trades.len(), second.trades.len()), (1, 1));
+        assert_eq!(head, chain_trades([0; 32], &first.trades));
+        // A consumer holding the previous head can check the next batch on its own
+        assert_eq!(state.trade_chain, chain_trades(head, &second.trades));
+        assert_ne!(state.trade_chain, chain_trades(head, &first.trades));
+
+        // The serialized state carries only the chain head, however many trades came before
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(!json.contains("\"bid_order\""));
+    }
+
+    #[test]
+    fn test_orders_lock_funds_and_trades_settle_them() {
+        let mut config = MarketConfig::default();
+        config.base.decimals = 0;
+        config.quantity_decimals = 0;
+        config.quote.decimals = 2;
+        let mut state = State::with_config(config);
+        let ctx = MatchContext::default();
+        let deposit = |id: &str, token: &str, amount| Action::Deposit {
+            address: address(id),
+            token: token.to_string(),
+            amount,
+        };
+        let balance = |state: &State, id: &str, token: &str| {
+            let balance = state.balances.get(&address(id), token);
+            (balance.available, balance.locked)
+        };
+
+        apply_action(&mut state, deposit("a1", "BASE", 10), &ctx).unwrap();
+        match_order(
+            &mut state,
+            order("a1", OrderType::Ask, "1.50", 10),
+            &ctx,
+            &PriceTime,
+        )
+        .unwrap();
+        assert_eq!(balance(&state, "a1", "BASE"), (0, 10));
+        assert_eq!(state.asks.best_order().unwrap().locked, 10);
+
+        // 4 at 2.00 needs 8.00 of quote, and the trader has 5.00
+        apply_action(&mut state, deposit("b1", "QUOTE", 500), &ctx).unwrap();
+        let required = MatchError::InsufficientBalance {
+            token: "QUOTE".to_string(),
+            required: 800,
+            available: 500,
+        };
+        let before = state.clone();
+        assert_eq!(
+            match_order(
+                &mut state,
+                order("b1", OrderType::Bid, "2.00", 4),
+                &ctx,
+                &PriceTime
+            ),
+            Err(required)
+        );
+        assert_eq!(state, before);
+
+        // Trading at the maker's 1.50 costs 6.00 and the rest of the lock comes back
+        apply_action(&mut state, deposit("b1", "QUOTE", 500), &ctx).unwrap();
+        match_order(
+            &mut state,
+            order("b1", OrderType::Bid, "2.00", 4),
+            &ctx,
+            &PriceTime,
+        )
+        .unwrap();
+        assert_eq!(balance(&state, "b1", "QUOTE"), (400, 0));
+        assert_eq!(balance(&state, "b1", "BASE"), (4, 0));
+        assert_eq!(balance(&state, "a1", "BASE"), (0, 6));
+        assert_eq!(balance(&state, "a1", "QUOTE"), (600, 0));
+
+        // A market bid buys only what its trader can pay for
+        apply_action(&mut state, deposit("b2", "QUOTE", 300), &ctx).unwrap();
+        let trades = match_order(
+            &mut state,
+            market("b2", OrderType::Bid, 10),
+            &ctx,
+            &PriceTime,
+        )
+        .unwrap()
+        .trades;
+        assert_eq!(trades[0].quantity, Quantity::new(2, 0));
+        assert_eq!(balance(&state, "b2", "QUOTE"), (0, 0));
+
+        // Unless it is fill-or-kill, when it must pay for all of it or buy nothing
+        apply_action(&mut state, deposit("b3", "QUOTE", 300), &ctx).unwrap();
+        let mut fok = market("b3", OrderType::Bid, 4);
+        fok.time_in_force = TimeInForce::FillOrKill;
+        let report = match_order(&mut state, fok.clone(), &ctx, &PriceTime).unwrap();
+        assert!(report.trades.is_empty());
+        assert_eq!(
+            report.events.last(),
+            Some(&OrderEvent::rejected(&fok, RejectReason::FillOrKill))
+        );
+        assert_eq!(balance(&state, "b3", "QUOTE"), (300, 0));
+        assert_eq!(
+            state.asks.best_order().unwrap().quantity,
+            Quantity::new(4, 0)
+        );
+
+        let withdraw = Action::Withdraw {
+            address: address("a1"),
+            token: "BASE".to_string(),
+            amount: 1,
+        };
+        let required = MatchError::InsufficientBalance {
+            token: "BASE".to_string(),
+            required: 1,
+            available: 0,
+        };
+        assert_eq!(
+            apply_action(&mut state, withdraw.clone(), &ctx),
+            Err(required)
+        );
+        apply_action(
+            &mut state,
+            Action::Cancel {
+                id: "a1".to_string(),
+            },
+            &ctx,
+        )
+        .unwrap();
+        assert_eq!(balance(&state, "a1", "BASE"), (4, 0));
+        apply_action(&mut state, withdraw, &ctx).unwrap();
+        assert_eq!(balance(&state, "a1", "BASE"), (3, 0));
+
+        let unknown = Action::Deposit {
+            address: address("a1"),
+            token: "OTHER".to_string(),
+            amount: 1,
+        };
+        assert_eq!(
+            apply_action(&mut state, unknown, &ctx),
+            Err(MatchError::UnknownToken("OTHER".to_string()))
+        );
+        let too_precise = order("a2", OrderType::Ask, "1.50", 15);
+        let too_precise = Order {
+            quantity: Quantity::new(15, 1),
+            ..too_precise
+        };
+        let error = MatchError::TooPrecise(Quantity::new(15, 1));
+        assert_eq!(
+            match_order(&mut state, too_precise, &ctx, &PriceTime),
+            Err(error)
+        );
+    }
+
+    #[test]
+    fn test_market_config_rules_are_enforced() {
+        let mut config = MarketConfig::default();
+        config.quote.decimals = 2;
+        config.tick_size = Some("0.05".parse().unwrap());
+        config.lot_size = Some("0.5".parse().unwrap());
+        config.min_quantity = Quantity::new(1, 0);
+        config.min_notional = 200;
+        let mut state = State::with_config(config);
+        for id in ["a1", "b1"] {
+            fund(&mut state, &address(id));
+        }
+        let ctx = MatchContext::default();
+        let sized = |price: &str, quantity: &str| {
+            let mut order = order("b1", OrderType::Bid, price, 0);
+            order.quantity = quantity.parse().unwrap();
+            order
+        };
+
+        let rejected = [
+            (
+                sized("1.02", "2"),
+                MatchError::OffTick("1.02".parse().unwrap()),
+            ),
+            (
+                sized("1.05", "2.2"),
+                MatchError::OffLot("2.2".parse().unwrap()),
+            ),
+            (
+                sized("1.05", "0.5"),
+                MatchError::BelowMinQuantity("0.5".parse().unwrap()),
+            ),
+            (
+                sized("1.00", "1.5"),
+                MatchError::BelowMinNotional {
+                    notional: 150,
+                    minimum: 200,
+                },
+            ),
+        ];
+        for (order, error) in rejected {
+            assert_eq!(match_order(&mut state, order, &ctx, &PriceTime), Err(error));
+        }
+        let mut stop = sized("1.05", "2");
+        stop.trigger = Some("1.01".parse().unwrap());
+        assert_eq!(
+            match_order(&mut state, stop, &ctx, &PriceTime),
+            Err(MatchError::OffTick("1.01".parse().unwrap()))
+        );
+
+        match_order(&mut state, sized("1.05", "2.5"), &ctx, &PriceTime).unwrap();
+        let amend = Action::Amend {
+            id: "b1".to_string(),
+            price: None,
+            quantity: Some("1.2".parse().unwrap()),
+        };
+        assert_eq!(
+            apply_action(&mut state, amend, &ctx),
+            Err(MatchError::OffLot("1.2".parse().unwrap()))
+        );
+
+        // Repricing lands on the tick grid rather than one decimal place away
+        let mut ask = order("a1", OrderType::Ask, "1.00", 2);
+        ask.post_only = Some(PostOnly::Reprice);
+        let report = match_order(&mut state, ask, &ctx, &PriceTime).unwrap();
+        assert_eq!(
+            report.post_only,
+            Some(PostOnlyOutcome::Repriced("1.10".parse().unwrap()))
+        );
+    }
+
+    #[test]
+    fn test_stop_order_without_funds_is_rejected_when_it_triggers() {
+        let mut state = State::new();
+        place(&mut state, order("a1", OrderType::Ask, "1.00", 10));
+        let mut stop = order("s1", OrderType::Bid, "1.00", 5);
+        stop.trigger = Some("1.00".parse().unwrap());
+        // Stops are not checked until they trigger, so this one goes in unfunded
+        match_order(&mut state, stop, &MatchContext::default(), &PriceTime).unwrap();
+
+        let events = place(&mut state, order("b1", OrderType::Bid, "1.00", 1)).events;
+        assert!(matches!(
+            events.last(),
+            Some(OrderEvent::Rejected {
+                order_id,
+                reason: RejectReason::Invalid(MatchError::InsufficientBalance { .. }),
+                ..
+            }) if order_id == "s1"
+        ));
+        assert!(state.stop_orders.is_empty());
+        assert_eq!(
+            state.asks.best_order().unwrap().total_quantity(),
+            Quantity::new(9, 0)
+        );
+    }
+
+    #[test]
+    fn test_balance_updates_carry_the_tree_to_the_new_root() {
+        let mut prev = State::new();
+        for id in ["a1", "b1"] {
+            fund(&mut prev, &address(id));
+        }
+        let mut next = prev.clone();
+        let actions = [
+            Action::Place(order("a1", OrderType::Ask, "1.00", 10)),
+            Action::Place(order("b1", OrderType::Bid, "1.00", 4)),
+            Action::Deposit {
+                address: address("c1"),
+                token: "BASE".to_string(),
+                amount: 7,
+            },
+        ];
+        let output = apply_actions(&mut next, &actions, &MatchContext::default());
+
+        let touched: Vec<(&str, &str)> = output
+            .balance_updates
+            .iter()
+            .map(|u| (u.trader.as_str(), u.token.as_str()))
+            .collect();
+        let (a1, b1, c1) = (address("a1"), address("b1"), address("c1"));
+        let expected = [
+            (&a1, "BASE"),
+            (&a1, "QUOTE"),
+            (&b1, "BASE"),
+            (&b1, "QUOTE"),
+            (&c1, "BASE"),
+        ];
+        assert_eq!(
+            touched,
+            expected.map(|(trader, token)| (trader.as_str(), token))
+        );
+
+        let mut tree = prev.balances.tree();
+        let leaves: Vec<_> = output
+            .balance_updates
+            .iter()
+            .map(BalanceUpdate::to_leaf)
+            .collect();
+        tree.batch_update(&leaves);
+        assert_ne!(prev.balance_root(), next.balance_root());
+        assert_eq!(tree.get_root(), next.balance_root().to_vec());
+    }
+
+    #[test]
+    fn test_exchange_routes_actions_and_shares_balances() {
+        let mut exchange = Exchange::new();
+        for base in ["AAA", "BBB"] {
+            let config = MarketConfig {
+                base: Token::new(base, 0),
+                quote: Token::new("QUOTE", 2),
+                ..Default::default()
+            };
+            exchange.add_market(&format!("{}-QUOTE", base), config);
+        }
+        let (seller, buyer) = (address("seller"), address("buyer"));
+        let place = |market: &str, id: &str, trader: &str, order_type, price| {
+            let order = Order {
+                market: market.to_string(),
+                address: trader.to_string(),
+                ..order(id, order_type, price, 5)
+            };
+            Action::Place(order)
+        };
+        let deposit = |trader: &str, token: &str, amount| Action::Deposit {
+            address: trader.to_string(),
+            token: token.to_string(),
+            amount,
+        };
+        for action in [
+            deposit(&seller, "AAA", 10),
+            deposit(&seller, "BBB", 10),
+            deposit(&buyer, "QUOTE", 1_000),
+        ] {
+            exchange
+                .apply_action(action, &MatchContext::default())
+                .unwrap();
+        }
+        let prev = exchange.clone();
+        let actions = [
+            place("AAA-QUOTE", "a1", &seller, OrderType::Ask, "1.00"),
+            place("BBB-QUOTE", "a2", &seller, OrderType::Ask, "2.00"),
+            // The buyer's 10.00 pays for this, and nothing is left for the other market
+            place("BBB-QUOTE", "b1", &buyer, OrderType::Bid, "2.00"),
+            place("AAA-QUOTE", "b2", &buyer, OrderType::Bid, "1.00"),
+            // Order ids are unique across markets while the order is open
+            place("BBB-QUOTE", "a1", &seller, OrderType::Ask, "3.00"),
+            place("CCC-QUOTE", "a3", &seller, OrderType::Ask, "1.00"),
+            Action::Cancel {
+                id: "a1".to_string(),
+            },
+        ];
+        let output = exchange.apply_actions(&actions, &MatchContext::default());
+
+        let rejections: Vec<(&str, &RejectReason)> = output
+            .events
+            .iter()
+            .filter_map(|event| match event {
+                OrderEvent::Rejected {
+                    order_id, reason, ..
+                } => Some((order_id.as_str(), reason)),
+                _ => None,
+            })
+            .collect();
+        let spent = MatchError::InsufficientBalance {
+            token: "QUOTE".to_string(),
+            required: 500,
+            available: 0,
+        };
+        assert_eq!(
+            rejections,
+            vec![
+                ("b2", &RejectReason::Invalid(spent)),
+                (
+                    "a1",
+                    &RejectReason::Invalid(MatchError::DuplicateOrderId("a1".to_string()))
+                ),
+                (
+                    "a3",
+                    &RejectReason::Invalid(MatchError::UnknownMarket("CCC-QUOTE".to_string()))
+                ),
+            ]
+        );
+        assert_eq!(output.trades.len(), 1);
+        assert_eq!(output.trades[0].bid_order.market, "BBB-QUOTE");
+        assert!(
+            exchange.market("AAA-QUOTE").unwrap().asks.is_empty(),
+            "The cancel found a1's market"
+        );
+        assert!(exchange
+            .markets
+            .values()
+            .all(|market| market.balances == Balances::default()));
+        let balance = |trader: &str, token: &str| exchange.balances.get(trader, token).available;
+        assert_eq!(
+            (
+                balance(&seller, "AAA"),
+                balance(&seller, "BBB"),
+                balance(&seller, "QUOTE")
+            ),
+            (10, 5, 1_000)
+        );
+        assert_eq!((balance(&buyer, "BBB"), balance(&buyer, "QUOTE")), (5, 0));
+
+        let values = exchange_public_values(
+            &prev,
+            &actions,
+            &MatchContext::default(),
+            &exchange,
+            &output,
+        );
+        assert_eq!(values.prevState, prev.commitment());
+        assert_eq!(values.newState, exchange.commitment());
+        assert_eq!(values.tradeChain, chain_trades([0; 32], &output.trades));
+        assert_eq!(values.balanceRoot, exchange.balance_root());
+        assert_eq!(values.traders.len(), 6);
+        assert_eq!(values.markets.len(), 6);
+        assert!(
+            values.markets.contains(&"CCC-QUOTE".to_string()),
+            "A rejected order still names its market"
+        );
+        assert_eq!(values.actionsHash, actions_hash(&actions));
+
+        // A single action extends the chain just as a batch does
+        let head = exchange.trade_chain;
+        let action = place("AAA-QUOTE", "b3", &buyer, OrderType::Bid, "1.00");
+        exchange
+            .apply_action(
+                place("AAA-QUOTE", "a4", &seller, OrderType::Ask, "1.00"),
+                &MatchContext::default(),
+            )
+            .unwrap();
+        exchange
+            .apply_action(deposit(&buyer, "QUOTE", 500), &MatchContext::default())
+            .unwrap();
+        let report = exchange
+            .apply_action(action, &MatchContext::default())
+            .unwrap();
+        assert_eq!(report.trades.len(), 1);
+        assert_eq!(exchange.trade_chain, chain_trades(head, &report.trades));
+    }
+
+    #[test]
+    fn test_pro_rata_shares_a_level_by_size() {
+        let pro_rata = MarketConfig {
+            allocation: Allocation::ProRata(ProRata::default()),
+            ..Default::default()
+        };
+        let mut state = State::with_config(pro_rata);
+        place(&mut state, order("a1", OrderType::Ask, "1.00", 10));
+        place(&mut state, order("a2", OrderType::Ask, "1.00", 30));
+        place(&mut state, order("a3", OrderType::Ask, "1.01", 10));
+
+        // The first level covers the bid, so the later and larger ask takes the larger share
+        let report = place(&mut state, order("b1", OrderType::Bid, "1.01", 20));
+        let fills: Vec<(&str, Quantity)> = report
+            .trades
+            .iter()
+            .map(|trade| (trade.ask_order.id.as_str(), trade.quantity))
+            .collect();
+        assert_eq!(
+            fills,
+            vec![("a1", Quantity::new(5, 0)), ("a2", Quantity::new(15, 0))]
+        );
+
+        // Whatever the market is configured with, `match_order` uses the policy it is given
+        fund(&mut state, &address("b2"));
+        let bid = order("b2", OrderType::Bid, "1.00", 10);
+        let report = match_order(&mut state, bid, &MatchContext::default(), &PriceTime).unwrap();
+        let fills: Vec<(&str, Quantity)> = report
+            .trades
+            .iter()
+            .map(|trade| (trade.ask_order.id.as_str(), trade.quantity))
+            .collect();
+        assert_eq!(
+            fills,
+            vec![("a1", Quantity::new(5, 0)), ("a2", Quantity::new(5, 0))]
+        );
+    }
+
+    #[test]
+    fn test_pro_rata_fill_or_kill_counts_shares_of_own_orders() {
+        let pro_rata = MarketConfig {
+            allocation: Allocation::ProRata(ProRata::default()),
+            ..Default::default()
+        };
+        let mut state = State::with_config(pro_rata);
+        place(&mut state, order("a1", OrderType::Ask, "1.00", 10));
+        let mut own = order("a2", OrderType::Ask, "1.00", 10);
+        own.address = address("b1");
+        place(&mut state, own);
+
+        // a1 alone covers the bid, but pro-rata gives b1's own ask half of it
+        let mut fok = order("b1", OrderType::Bid, "1.00", 10);
+        fok.time_in_force = TimeInForce::FillOrKill;
+        let report = place(&mut state, fok.clone());
+        assert!(report.trades.is_empty());
+        assert_eq!(
+            report.events.last(),
+            Some(&OrderEvent::rejected(&fok, RejectReason::FillOrKill))
+        );
+        assert_eq!(state.asks.len(), 2);
+
+        // Cancel-oldest takes the own ask away and a1 fills the bid in full
+        fok.self_trade_prevention = Some(SelfTradePrevention::CancelOldest);
+        let report = place(&mut state, fok);
+        assert!(report.trades.iter().all(|trade| trade.ask_order.id == "a1"));
+        assert_eq!(
+            report
+                .trades
+                .iter()
+                .map(|trade| trade.quantity.lots)
+                .sum::<u64>(),
+            10
+        );
+        assert!(state.asks.is_empty());
+    }
+
+    #[test]
+    fn test_batch_auction_clears_at_one_price() {
+        let auction = MarketConfig {
+            matching_mode: MatchingMode::BatchAuction,
+            ..Default::default()
+        };
+        let mut state = State::with_config(auction);
+        let ioc = |id: &str, price: &str| {
+            let mut order = order(id, OrderType::Bid, price, 5);
+            order.time_in_force = TimeInForce::ImmediateOrCancel;
+            order
+        };
+        let orders = vec![
+            order("b1", OrderType::Bid, "1.10", 10),
+            order("b2", OrderType::Bid, "1.05", 10),
+            ioc("b3", "1.20"),
+            ioc("b4", "0.90"),
+            order("a1", OrderType::Ask, "1.00", 5),
+            order("a2", OrderType::Ask, "1.04", 10),
+        ];
+        for order in &orders {
+            fund(&mut state, &order.address);
+        }
+        let mut actions: Vec<Action> = orders.into_iter().map(Action::Place).collect();
+        actions.push(Action::Place(market("m1", OrderType::Bid, 5)));
+        let mut reversed = state.clone();
+        let output = apply_actions(&mut state, &actions, &MatchContext::default());
+
+        // 1.04 through 1.10 all trade 15, and only 1.10 leaves no imbalance
+        let clearing = Clearing {
+            market: String::new(),
+            price: "1.10".parse().unwrap(),
+            volume: Quantity::new(15, 0),
+        };
+        assert_eq!(output.clearings, vec![clearing]);
+        assert!(output
+            .trades
+            .iter()
+            .all(|trade| trade.price == "1.10".parse().unwrap()));
+        let pairs: Vec<(&str, &str)> = output
+            .trades
+            .iter()
+            .map(|trade| (trade.bid_order.id.as_str(), trade.ask_order.id.as_str()))
+            .collect();
+        assert_eq!(pairs, vec![("b3", "a1"), ("b1", "a2")]);
+        assert_eq!(state.last_price, Some("1.10".parse().unwrap()));
+
+        // The unfilled immediate-or-cancel bid goes, the resting one stays
+        assert_eq!(
+            state
+                .bids
+                .orders()
+                .map(|order| order.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["b2"]
+        );
+        assert!(output.events.iter().any(|event| {
+            matches!(event, OrderEvent::Cancelled { order_id, .. } if order_id == "b4")
+        }));
+        assert!(output.events.contains(&OrderEvent::rejected(
+            &market("m1", OrderType::Bid, 5),
+            RejectReason::Invalid(MatchError::UnsupportedInAuction)
+        )));
+        // b3 locked 6.00 at its own limit and paid 5.50
+        let quote = state.balances.get(&address("b3"), "QUOTE");
+        assert_eq!(
+            (quote.available, quote.locked),
+            (FUNDS - 55 * 10u128.pow(17), 0)
+        );
+
+        // Arrival order within the batch does not change the outcome
+        actions.reverse();
+        let other = apply_actions(&mut reversed, &actions, &MatchContext::default());
+        assert_eq!(other.clearings, output.clearings);
+        assert_eq!(reversed.balance_root(), state.balance_root());
+
+        let values = public_values(
+            &State::new(),
+            &actions,
+            &MatchContext::default(),
+            &state,
+            &output,
+        );
+        assert_eq!(values.clearingsHash, clearings_hash(&output.clearings));
+    }
+
+    #[test]
+    fn test_batch_auction_expires_orders_before_clearing() {
+        let auction = MarketConfig {
+            matching_mode: MatchingMode::BatchAuction,
+            ..Default::default()
+        };
+        let mut state = State::with_config(auction);
+        let mut bid = order("b1", OrderType::Bid, "1.10", 10);
+        bid.time_in_force = TimeInForce::GoodTilTime(100);
+        place(&mut state, bid);
+        place(&mut state, order("a1", OrderType::Ask, "1.00", 10));
+
+        // An empty batch after the bid's expiry still clears, without it
+        let output = apply_actions(&mut state, &[], &MatchContext { timestamp: 500 });
+        assert!(output.trades.is_empty());
+        assert!(output.clearings.is_empty());
+        assert!(output.events.iter().any(
+            |event| matches!(event, OrderEvent::Expired { order_id, .. } if order_id == "b1")
+        ));
+        assert!(state.bids.is_empty());
+        assert_eq!(state.balances.get(&address("b1"), "QUOTE").locked, 0);
+        assert_eq!(state.asks.len(), 1);
+    }
+
+    #[test]
+    fn test_clearing_price_breaks_ties_towards_last_price() {
+        let auction = MarketConfig {
+            matching_mode: MatchingMode::BatchAuction,
+            ..Default::default()
+        };
+        let mut state = State::with_config(auction);
+        place(&mut state, order("b1", OrderType::Bid, "1.10", 10));
+        place(&mut state, order("a1", OrderType::Ask, "1.00", 10));
+        // Crossing orders rest until the auction
+        assert_eq!(state.best_bid(), Some("1.10".parse().unwrap()));
+        assert_eq!(state.best_ask(), Some("1.00".parse().unwrap()));
+        assert_eq!(clearing_price(&state), Some("1.00".parse().unwrap()));
+        state.last_price = Some("1.08".parse().unwrap());
+        assert_eq!(clearing_price(&state), Some("1.10".parse().unwrap()));
+
+        let mut stop = order("s1", OrderType::Bid, "1.20", 1);
+        stop.trigger = Some("1.15".parse().unwrap());
+        assert_eq!(
+            match_order(&mut state, stop, &MatchContext::default(), &PriceTime).unwrap_err(),
+            MatchError::UnsupportedInAuction
+        );
+    }
+
+    #[test]
+    fn test_public_values_commit_to_both_states() {
+        let mut prev = State::new();
+        fund(&mut prev, &address("a1"));
+        fund(&mut prev, &address("b1"));
+        let mut next = prev.clone();
+        let actions = vec![
+            Action::Place(order("a1", OrderType::Ask, "1.05", 10)),
+            Action::Cancel {
+                id: "missing".to_string(),
+            },
+            Action::Place(market("b1", OrderType::Bid, 4)),
+        ];
+        let ctx = MatchContext {
+            timestamp: 1_700_000_000,
+        };
+        let output = apply_actions(&mut next, &actions, &ctx);
+        let values = public_values(&prev, &actions, &ctx, &next, &output);
+
+        assert_eq!(values.prevState, prev.commitment());
+        assert_eq!(values.newState, next.commitment());
+        assert_eq!(values.tradeChain, chain_trades([0; 32], &output.trades));
+        assert_eq!(values.balanceRoot, next.balance_root());
+        assert_eq!(values.eventsHash, event_hash(&output.events));
+        assert_eq!(values.actionsHash, actions_hash(&actions));
+        assert_eq!(values.timestamp, ctx.timestamp);
+        assert_eq!(values.orderTypes, vec![1, 0]);
+        // At the market's eight decimal places, so 1.05 and 1.050 commit alike
+        assert_eq!(values.price, vec![U256::from(105_000_000u64), U256::ZERO]);
+        assert_eq!(
+            values.quantity[0],
+            U256::from(10u64) * U256::from(10u64).pow(U256::from(8u64))
+        );
+        assert_eq!(
+            values.traders[0],
+            address("a1").parse::<alloy_primitives::Address>().unwrap()
+        );
+
+        let encoded = PublicValuesStruct::abi_encode(&values);
+        let decoded = PublicValuesStruct::abi_decode(&encoded, true).unwrap();
+        assert_eq!(decoded.newState, values.newState);
+    }
+}
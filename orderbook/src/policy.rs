@@ -0,0 +1,177 @@
+//! How an incoming order is shared among the orders resting at the price it trades at.
+
+use crate::Quantity;
+use alloy_primitives::U256;
+use serde::{Deserialize, Serialize};
+
+/// Decides how much of an incoming order each order at one price level fills. Implementations
+/// must only use integer arithmetic, so that the guest and native code always allocate alike.
+pub trait MatchingPolicy {
+    /// Shares `quantity` among a level, given as the displayed quantities of its orders oldest
+    /// first. Returns one share per order, none more than the order displays, that together come
+    /// to the smaller of `quantity` and the level's total. `lot` is the market's lot size.
+    fn allocate(
+        &self,
+        level: &[Quantity],
+        quantity: Quantity,
+        lot: Option<Quantity>,
+    ) -> Vec<Quantity>;
+}
+
+/// Price-time priority: the oldest order fills first, then the next, until the quantity runs out.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub struct PriceTime;
+
+impl MatchingPolicy for PriceTime {
+    fn allocate(
+        &self,
+        level: &[Quantity],
+        quantity: Quantity,
+        _lot: Option<Quantity>,
+    ) -> Vec<Quantity> {
+        let mut left = quantity;
+        level
+            .iter()
+            .map(|shown| {
+                let share = left.min(*shown);
+                left -= share;
+                share
+            })
+            .collect()
+    }
+}
+
+/// Pro-rata: each order fills in proportion to what it displays, rounded down to whole lots, or
+/// to the finest scale involved when the market has no lot size. Whatever the rounding leaves
+/// over goes out a lot at a time to the oldest orders first, in as many rounds as it takes.
+///
+/// With `top_order_priority` the oldest order fills first, as under price-time, and the others
+/// share what remains of the quantity.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub struct ProRata {
+    pub top_order_priority: bool,
+}
+
+impl MatchingPolicy for ProRata {
+    fn allocate(
+        &self,
+        level: &[Quantity],
+        quantity: Quantity,
+        lot: Option<Quantity>,
+    ) -> Vec<Quantity> {
+        let scale = level
+            .iter()
+            .chain([&quantity])
+            .chain(&lot)
+            .map(|q| q.scale)
+            .max()
+            .unwrap_or(0);
+        let shown: Vec<u128> = level.iter().map(|q| q.units_at(scale)).collect();
+        let step = lot.map_or(1, |lot| lot.units_at(scale).max(1));
+        let mut left = quantity.units_at(scale).min(shown.iter().sum());
+        let mut shares = vec![0; shown.len()];
+
+        let first = usize::from(self.top_order_priority).min(shown.len());
+        if first == 1 {
+            shares[0] = shown[0].min(left);
+            left -= shares[0];
+        }
+        let rest: u128 = shown[first..].iter().sum();
+        if left > 0 {
+            // Rounds each share down to whole steps; `left * shown` can exceed a u128
+            for (share, shown) in shares[first..].iter_mut().zip(&shown[first..]) {
+                let exact = (U256::from(left) * U256::from(*shown) / U256::from(rest)).to::<u128>();
+                *share = exact - exact % step;
+            }
+            left -= shares[first..].iter().sum::<u128>();
+        }
+        // Ends because `left` never exceeds what the orders can still take
+        while left > 0 {
+            for (share, shown) in shares[first..].iter_mut().zip(&shown[first..]) {
+                let extra = step.min(shown - *share).min(left);
+                *share += extra;
+                left -= extra;
+            }
+        }
+        shares
+            .into_iter()
+            .map(|share| Quantity::from_units(share, scale).expect("Quantity overflow"))
+            .collect()
+    }
+}
+
+/// The matching policy a market uses, as recorded in its `MarketConfig`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum Allocation {
+    #[default]
+    PriceTime,
+    ProRata(ProRata),
+}
+
+impl MatchingPolicy for Allocation {
+    fn allocate(
+        &self,
+        level: &[Quantity],
+        quantity: Quantity,
+        lot: Option<Quantity>,
+    ) -> Vec<Quantity> {
+        match self {
+            Allocation::PriceTime => PriceTime.allocate(level, quantity, lot),
+            Allocation::ProRata(pro_rata) => pro_rata.allocate(level, quantity, lot),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantities(values: &[&str]) -> Vec<Quantity> {
+        values.iter().map(|value| value.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_price_time_fills_oldest_first() {
+        let shares = PriceTime.allocate(&quantities(&["3", "5", "2"]), "6".parse().unwrap(), None);
+        assert_eq!(shares, quantities(&["3", "3", "0"]));
+    }
+
+    #[test]
+    fn test_pro_rata_gives_rounding_remainders_to_the_oldest() {
+        let level = quantities(&["10", "20", "30"]);
+        let pro_rata = ProRata::default();
+        // 1.17, 2.33 and 3.5 round down to 1, 2 and 3, and the lot left over goes to the oldest
+        assert_eq!(
+            pro_rata.allocate(&level, "7".parse().unwrap(), None),
+            quantities(&["2", "2", "3"])
+        );
+        // Without a lot size the shares are exact at the finest scale involved
+        assert_eq!(
+            pro_rata.allocate(&level, "0.6".parse().unwrap(), None),
+            quantities(&["0.1", "0.2", "0.3"])
+        );
+        assert_eq!(
+            pro_rata.allocate(&level, "100".parse().unwrap(), None),
+            level
+        );
+
+        // Each share is whole lots, handed out a lot at a time
+        let lot = Some("0.5".parse().unwrap());
+        let shares = pro_rata.allocate(&quantities(&["1", "1", "1"]), "1".parse().unwrap(), lot);
+        assert_eq!(shares, quantities(&["0.5", "0.5", "0"]));
+    }
+
+    #[test]
+    fn test_pro_rata_top_order_fills_first() {
+        let pro_rata = ProRata {
+            top_order_priority: true,
+        };
+        let shares =
+            pro_rata.allocate(&quantities(&["4", "10", "30"]), "14".parse().unwrap(), None);
+        assert_eq!(shares, quantities(&["4", "3", "7"]));
+        assert_eq!(pro_rata.allocate(&[], "1".parse().unwrap(), None), vec![]);
+        let shares =
+            Allocation::ProRata(pro_rata).allocate(&quantities(&["4"]), "1".parse().unwrap(), None);
+        assert_eq!(shares, quantities(&["1"]));
+    }
+}
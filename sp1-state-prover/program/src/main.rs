@@ -1,5 +1,5 @@
-//! A simple program that takes a number `n` as input, and writes the `n-1`th and `n`th fibonacci
-//! number as an output.
+//! The guest: reads an `Exchange`, a batch of actions and the `MatchContext` to run them at,
+//! applies the batch, and commits the ABI-encoded `PublicValuesStruct` for it.
 
 // These two lines are necessary for the program to properly compile.
 //
@@ -9,22 +9,20 @@
 sp1_zkvm::entrypoint!(main);
 
 use alloy_sol_types::SolType;
-use orderbook::{Order, State, match_order,PublicValuesStruct};
-use serde::{Serialize, Deserialize};
-
+use orderbook::{exchange_public_values, Action, Exchange, MatchContext, PublicValuesStruct};
 
 pub fn main() {
-    let mut curr_state: State = sp1_zkvm::io::read();
-    let transactions : Vec<Order> = sp1_zkvm::io::read();
-    let res_state: State = sp1_zkvm::io::read();
+    let prev_state: Exchange = sp1_zkvm::io::read();
+    let transactions: Vec<Action> = sp1_zkvm::io::read();
+    let ctx: MatchContext = sp1_zkvm::io::read();
 
-    for tx in transactions.iter(){
-        curr_state = match_order(curr_state, tx.clone());
-    }
+    // A rejected action leaves the book untouched, so the batch simply moves on. Orders name
+    // their market, so one batch may touch any number of them.
+    let mut curr_state = prev_state.clone();
+    let output = curr_state.apply_actions(&transactions, &ctx);
 
-    if(res_state == curr_state){
-        sp1_zkvm::io::commit(&true);
-    }else{
-        sp1_zkvm::io::commit(&false);
-    }
+    // The verifier checks `prevState` against the commitment it already holds and adopts
+    // `newState`, so the resulting state never has to be passed in
+    let values = exchange_public_values(&prev_state, &transactions, &ctx, &curr_state, &output);
+    sp1_zkvm::io::commit_slice(&PublicValuesStruct::abi_encode(&values));
 }
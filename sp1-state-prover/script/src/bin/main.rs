@@ -1,13 +1,15 @@
 use alloy_sol_types::SolType;
 use clap::Parser;
-use orderbook::{match_order, Order, OrderType, State, Trade};
+use orderbook::{
+    exchange_public_values, Action, Exchange, MarketConfig, MatchContext, Order, OrderType, Price,
+    PublicValuesStruct, Quantity, Token,
+};
+use serde::{Deserialize, Serialize};
 use sp1_sdk::{include_elf, ProverClient, SP1Stdin};
-use serde::{Serialize, Deserialize};
 
 /// The ELF (executable and linkable format) file for the Succinct RISC-V zkVM.
 pub const FIBONACCI_ELF: &[u8] = include_elf!("fibonacci-program");
 
-
 /// The arguments for the command.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -23,6 +25,11 @@ struct Args {
 
     #[clap(long, default_value = "20")]
     b: u32,
+
+    /// Number of resting orders per side to seed the book with before the batch, for comparing
+    /// cycle counts on deep books.
+    #[clap(long, default_value = "0")]
+    depth: u64,
 }
 
 fn main() {
@@ -41,21 +48,119 @@ fn main() {
     let client = ProverClient::new();
 
     //Generating the inputs and outputs
-    let start_state = State{pending_ask_orders:vec![], pending_bid_orders: vec![], trades: vec![]};
-    let mut transactions: Vec<Order> = vec![];
-    transactions.push(Order{id:"123".to_string(), address: "123".to_string(), order_type:OrderType::Bid, price: 1.05, quantity: 1000});
-    transactions.push(Order{id:"123".to_string(), address: "123".to_string(), order_type:OrderType::Ask, price: 1.05, quantity: 1000});
+    // Two markets that share a quote token, so the batch below touches both
+    let mut start_state = Exchange::new();
+    let markets = ["AAA-QUOTE", "BBB-QUOTE"];
+    for (market, base) in markets.iter().zip(["AAA", "BBB"]) {
+        let config = MarketConfig {
+            base: Token::new(base, 18),
+            quote: Token::new("QUOTE", 18),
+            ..Default::default()
+        };
+        start_state.add_market(market, config);
+    }
+    let in_market = |market: &str, order: Order| {
+        Action::Place(Order {
+            market: market.to_string(),
+            ..order
+        })
+    };
+    let maker = "0x3333333333333333333333333333333333333333";
+    // Plenty of every token for every seeded order
+    let funds = 10u128.pow(30);
+    for token in ["AAA", "BBB", "QUOTE"] {
+        let deposit = Action::Deposit {
+            address: maker.to_string(),
+            token: token.to_string(),
+            amount: funds,
+        };
+        start_state
+            .apply_action(deposit, &MatchContext::default())
+            .unwrap();
+    }
+    for i in 0..args.depth {
+        let bid = Order::limit(
+            &format!("seed-bid-{}", i),
+            maker,
+            OrderType::Bid,
+            Price::new(100 - i % 50, 2),
+            Quantity::new(10, 0),
+        );
+        let ask = Order::limit(
+            &format!("seed-ask-{}", i),
+            maker,
+            OrderType::Ask,
+            Price::new(110 + i % 50, 2),
+            Quantity::new(10, 0),
+        );
+        start_state
+            .apply_action(in_market(markets[0], bid), &MatchContext::default())
+            .unwrap();
+        start_state
+            .apply_action(in_market(markets[0], ask), &MatchContext::default())
+            .unwrap();
+    }
+    let mut transactions: Vec<Action> = vec![];
+    let buyer = "0x1111111111111111111111111111111111111111";
+    let seller = "0x2222222222222222222222222222222222222222";
+    for token in ["AAA", "BBB"] {
+        transactions.push(Action::Deposit {
+            address: seller.to_string(),
+            token: token.to_string(),
+            amount: funds,
+        });
+    }
+    transactions.push(Action::Deposit {
+        address: buyer.to_string(),
+        token: "QUOTE".to_string(),
+        amount: funds,
+    });
+    for (i, market) in markets.iter().enumerate() {
+        let bid = Order::limit(
+            &format!("bid-{}", i),
+            buyer,
+            OrderType::Bid,
+            Price::new(105, 2),
+            Quantity::new(1000, 0),
+        );
+        let ask = Order::limit(
+            &format!("ask-{}", i),
+            seller,
+            OrderType::Ask,
+            Price::new(105, 2),
+            Quantity::new(1000, 0),
+        );
+        transactions.push(in_market(market, bid));
+        transactions.push(in_market(market, ask));
+    }
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let ctx = MatchContext { timestamp };
 
     let mut last_state = start_state.clone();
-    for tx in transactions.iter(){
-        last_state = match_order(last_state, tx.clone());
+    let output = last_state.apply_actions(&transactions, &ctx);
+    for event in output.events.iter() {
+        println!("{:?}", event);
+    }
+    for trade in output.trades.iter() {
+        println!("{:?}", trade);
+    }
+    for update in output.balance_updates.iter() {
+        println!("{:?}", update);
+    }
+    for clearing in output.clearings.iter() {
+        println!("{:?}", clearing);
     }
     println!("{:?}", last_state);
+    let expected = exchange_public_values(&start_state, &transactions, &ctx, &last_state, &output);
     // Setup the inputs.
     let mut stdin = SP1Stdin::new();
     stdin.write(&start_state);
     stdin.write(&transactions);
-    stdin.write(&last_state);
+    stdin.write(&ctx);
     // println!("n: {}", args.n);
 
     if args.execute {
@@ -63,20 +168,28 @@ fn main() {
         let (output, report) = client.execute(FIBONACCI_ELF, stdin).run().unwrap();
         println!("Program executed successfully.");
 
-        // Read the output.
-        // let decoded = PublicValuesStruct::abi_decode(output.as_slice(), true).unwrap();
-        // let PublicValuesStruct { n, a, b } = decoded;
-        // println!("n: {}", n);
-        // println!("a: {}", a);
-        // println!("b: {}", b);
-
-        // let (expected_a, expected_b) = fibonacci_lib::fibonacci(n);
-        // assert_eq!(a, expected_a);
-        // assert_eq!(b, expected_b);
-        // println!("Values are correct!");
+        // Read the output and check it against the batch run locally.
+        let decoded = PublicValuesStruct::abi_decode(output.as_slice(), true).unwrap();
+        println!("prevState: {}", decoded.prevState);
+        println!("actionsHash: {}", decoded.actionsHash);
+        println!("timestamp: {}", decoded.timestamp);
+        println!("newState: {}", decoded.newState);
+        println!("eventsHash: {}", decoded.eventsHash);
+        println!("tradeChain: {}", decoded.tradeChain);
+        println!("balanceRoot: {}", decoded.balanceRoot);
+        println!("clearingsHash: {}", decoded.clearingsHash);
+        assert_eq!(decoded.prevState, expected.prevState);
+        assert_eq!(decoded.actionsHash, expected.actionsHash);
+        assert_eq!(decoded.timestamp, expected.timestamp);
+        assert_eq!(decoded.markets, expected.markets);
+        assert_eq!(decoded.newState, expected.newState);
+        assert_eq!(decoded.eventsHash, expected.eventsHash);
+        assert_eq!(decoded.tradeChain, expected.tradeChain);
+        assert_eq!(decoded.balanceRoot, expected.balanceRoot);
+        assert_eq!(decoded.clearingsHash, expected.clearingsHash);
+        println!("Values are correct!");
 
         // Record the number of cycles executed.
-        println!("Output is :- {:?}", output.as_slice());
         println!("Number of cycles: {}", report.total_instruction_count());
     } else {
         // Setup the program for proving.